@@ -1,12 +1,114 @@
+use crate::expression::Program;
+use crate::filters::{BiquadFilter, Crossover, Filter, FilterType, BUTTERWORTH_Q};
+use crate::neural::NeuralModel;
 use nih_plug::params::enums::Enum;
-use std::fmt::{self, Display};
+use std::f32::consts::FRAC_PI_2;
+use std::fmt;
+use std::marker::PhantomData;
 
-pub type Distortion = fn(f32, f32, &mut [f32]);
+/// A distortion processing one channel. Unlike a plain function, it may keep a state from one call
+/// to the next, which is why `DistAll` owns one instance per channel.
+pub trait DistortionProcessor {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]);
+    /// Clear the state, memoryless distortions don't need to implement it
+    fn reset(&mut self) {}
+    /// Rate at which `process` is called, that is the oversampled rate. Called for every buffer,
+    /// implementations should only recompute their coefficients when it actually changed.
+    fn set_sample_rate(&mut self, _sample_rate: f32) {}
+}
 
 #[derive(Enum, Debug, PartialEq, Clone, Copy)]
 pub enum DistortionType {
     SOFT,
     HARD,
+    TANH,
+    ATAN,
+    ALGEBRAIC,
+    KNEE,
+    FOLD,
+    BITCRUSH,
+    DOWNSAMPLE,
+    FULLWAVE,
+    HALFWAVE,
+    RECTIFY,
+    HARMONICS,
+    CUSTOM,
+    EXPRESSION,
+    TAPE,
+    DIODE,
+    TUBE,
+    NEURAL,
+}
+
+/// How the `FOLD` distortion reflects the signal exceeding its threshold
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum FoldShape {
+    #[name = "Triangle"]
+    Triangle,
+    #[name = "Sine"]
+    Sine,
+}
+
+/// Diodes of the `DIODE` clipper, from the softest and lowest clipping to the hardest and highest
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum DiodeType {
+    #[name = "Germanium"]
+    Germanium,
+    #[name = "Silicon"]
+    Silicon,
+    #[name = "LED"]
+    Led,
+}
+
+impl DiodeType {
+    /// Saturation current (A) and ideality factor of the Shockley equation
+    fn shockley(self) -> (f64, f64) {
+        match self {
+            // 1N34A
+            Self::Germanium => (2.0e-7, 1.3),
+            // 1N4148
+            Self::Silicon => (2.52e-9, 1.752),
+            // Red LED
+            Self::Led => (1.0e-19, 2.0),
+        }
+    }
+}
+
+/// Antiderivative anti-aliasing order applied to the clipping functions
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum AdaaOrder {
+    #[name = "Off"]
+    Off,
+    #[name = "1st order"]
+    First,
+    #[name = "2nd order"]
+    Second,
+}
+
+impl AdaaOrder {
+    /// Group delay added by the antiderivative differences, in samples at the rate it runs at
+    pub fn delay(self) -> f32 {
+        match self {
+            Self::Off => 0.0,
+            Self::First => 0.5,
+            Self::Second => 1.0,
+        }
+    }
+}
+
+/// Samples the morph copies at once, on the stack
+const MORPH_CHUNK_SIZE: usize = 64;
+
+impl DistortionType {
+    /// Lo-fi distortions are all about aliasing, they always run at the host's rate
+    pub fn bypasses_oversampling(self) -> bool {
+        matches!(self, Self::BITCRUSH | Self::DOWNSAMPLE)
+    }
+
+    /// Only the clippers have antiderivatives, the other types don't follow the ADAA order
+    pub fn uses_adaa(self) -> bool {
+        matches!(self, Self::SOFT | Self::HARD)
+    }
 }
 
 impl fmt::Display for DistortionType {
@@ -15,41 +117,2048 @@ impl fmt::Display for DistortionType {
     }
 }
 
-impl DistortionType {
-    pub fn function(self: DistortionType) -> Distortion {
-        match self {
-            Self::HARD => hard_clipping,
-            Self::SOFT => soft_clipping,
+//
+// The distortion of a single channel. It holds one instance of every distortion type, so that
+// switching the DistortionType never allocates on the audio thread, and offsets the signal by
+// `bias` before the selected curve, which makes every curve asymmetric. A second type may be
+// blended with the selected one. When only one of them uses ADAA, the other one is delayed by the
+// same kernel (see `AdaaDelay`), so that the crossfade stays in phase.
+//
+pub struct DistortionBank {
+    distortion_type: DistortionType,
+    distortion_type_b: DistortionType,
+    /// Weight of the second type at the end of the previous block, and the one to reach at the
+    /// end of the next
+    morph: f32,
+    morph_target: f32,
+    bias: f32,
+    adaa_order: AdaaOrder,
+    // Inputs of the first and second types
+    delay_a: AdaaDelay,
+    delay_b: AdaaDelay,
+    soft_clipping: Adaa<SoftClipping>,
+    hard_clipping: Adaa<HardClipping>,
+    tanh: Waveshaper,
+    atan: Waveshaper,
+    algebraic: Waveshaper,
+    knee: KneeClipper,
+    fold: Wavefolder,
+    bitcrush: Bitcrusher,
+    downsample: SampleRateReducer,
+    full_wave: Rectifier,
+    half_wave: Rectifier,
+    rectify: Rectifier,
+    harmonics: HarmonicGenerator,
+    custom: CustomCurve,
+    expression: ExpressionShaper,
+    tape: Hysteresis,
+    diode: DiodeClipper,
+    tube: Triode,
+    neural: NeuralAmp,
+    sample_rate: f32,
+}
+
+impl DistortionBank {
+    /// Banks running side by side must be given different seeds, so that their noises are not
+    /// correlated
+    pub fn new(sample_rate: f32, seed: u32) -> Self {
+        Self {
+            distortion_type: DistortionType::SOFT,
+            distortion_type_b: DistortionType::SOFT,
+            morph: 0.0,
+            morph_target: 0.0,
+            bias: 0.0,
+            adaa_order: AdaaOrder::Off,
+            delay_a: AdaaDelay::new(),
+            delay_b: AdaaDelay::new(),
+            soft_clipping: Adaa::new(AdaaOrder::Off),
+            hard_clipping: Adaa::new(AdaaOrder::Off),
+            tanh: Waveshaper::new(tanh_saturation),
+            atan: Waveshaper::new(atan_saturation),
+            algebraic: Waveshaper::new(algebraic_saturation),
+            knee: KneeClipper::new(),
+            fold: Wavefolder::new(),
+            bitcrush: Bitcrusher::new(seed),
+            downsample: SampleRateReducer::new(sample_rate),
+            full_wave: Rectifier::new(1.0),
+            half_wave: Rectifier::new(0.5),
+            rectify: Rectifier::new(0.5),
+            harmonics: HarmonicGenerator::new(),
+            custom: CustomCurve::new(),
+            expression: ExpressionShaper::new(),
+            tape: Hysteresis::new(),
+            diode: DiodeClipper::new(sample_rate),
+            tube: Triode::new(sample_rate),
+            neural: NeuralAmp { model: None },
+            sample_rate,
+        }
+    }
+
+    /// The newly selected distortion starts from a cleared state
+    pub fn set_type(&mut self, distortion_type: DistortionType) {
+        if distortion_type != self.distortion_type {
+            self.distortion_type = distortion_type;
+            self.selected().reset();
+        }
+    }
+
+    /// Second distortion blended with the selected one, from 0 (first only) to 1 (second only).
+    /// The weight is ramped over the next block to avoid clicks.
+    pub fn set_morph(&mut self, distortion_type: DistortionType, morph: f32) {
+        if distortion_type != self.distortion_type_b {
+            self.distortion_type_b = distortion_type;
+            if distortion_type != self.distortion_type {
+                self.processor(distortion_type).reset();
+            }
+        }
+        self.morph_target = morph;
+    }
+
+    fn selected(&mut self) -> &mut dyn DistortionProcessor {
+        self.processor(self.distortion_type)
+    }
+
+    fn processor(&mut self, distortion_type: DistortionType) -> &mut dyn DistortionProcessor {
+        match distortion_type {
+            DistortionType::SOFT => &mut self.soft_clipping,
+            DistortionType::HARD => &mut self.hard_clipping,
+            DistortionType::TANH => &mut self.tanh,
+            DistortionType::ATAN => &mut self.atan,
+            DistortionType::ALGEBRAIC => &mut self.algebraic,
+            DistortionType::KNEE => &mut self.knee,
+            DistortionType::FOLD => &mut self.fold,
+            DistortionType::BITCRUSH => &mut self.bitcrush,
+            DistortionType::DOWNSAMPLE => &mut self.downsample,
+            DistortionType::FULLWAVE => &mut self.full_wave,
+            DistortionType::HALFWAVE => &mut self.half_wave,
+            DistortionType::RECTIFY => &mut self.rectify,
+            DistortionType::HARMONICS => &mut self.harmonics,
+            DistortionType::CUSTOM => &mut self.custom,
+            DistortionType::EXPRESSION => &mut self.expression,
+            DistortionType::TAPE => &mut self.tape,
+            DistortionType::DIODE => &mut self.diode,
+            DistortionType::TUBE => &mut self.tube,
+            DistortionType::NEURAL => &mut self.neural,
+        }
+    }
+
+    fn processors(&mut self) -> [&mut dyn DistortionProcessor; 19] {
+        [
+            &mut self.soft_clipping,
+            &mut self.hard_clipping,
+            &mut self.tanh,
+            &mut self.atan,
+            &mut self.algebraic,
+            &mut self.knee,
+            &mut self.fold,
+            &mut self.bitcrush,
+            &mut self.downsample,
+            &mut self.full_wave,
+            &mut self.half_wave,
+            &mut self.rectify,
+            &mut self.harmonics,
+            &mut self.custom,
+            &mut self.expression,
+            &mut self.tape,
+            &mut self.diode,
+            &mut self.tube,
+            &mut self.neural,
+        ]
+    }
+
+    /// Offset added to the signal after the pre gain, in the curves' input unit
+    pub fn set_bias(&mut self, bias: f32) {
+        self.bias = bias;
+    }
+
+    pub fn set_knee(&mut self, knee: f32) {
+        self.knee.set_knee(knee);
+    }
+
+    pub fn set_fold(&mut self, threshold: f32, symmetry: f32, shape: FoldShape) {
+        self.fold.set_fold(threshold, symmetry, shape);
+    }
+
+    pub fn set_bit_depth(&mut self, bit_depth: f32, dither: bool) {
+        self.bitcrush.set_bit_depth(bit_depth, dither);
+    }
+
+    pub fn set_downsample_rate(&mut self, target_rate: f32) {
+        self.downsample.set_target_rate(target_rate);
+    }
+
+    pub fn set_rectify(&mut self, amount: f32) {
+        self.rectify.set_amount(amount);
+    }
+
+    /// Levels of the harmonics 2 to 8, relative to the fundamental
+    pub fn set_harmonics(&mut self, amounts: [f32; HARMONICS_COUNT]) {
+        self.harmonics.set_amounts(amounts);
+    }
+
+    /// Rebuild the CUSTOM lookup table, only call it when the control points changed
+    pub fn set_custom_curve(&mut self, points: &[(f32, f32)]) {
+        self.custom.set_points(points);
+    }
+
+    /// Copies the compiled EXPRESSION, which does not allocate
+    pub fn set_expression(&mut self, program: &Program) {
+        self.expression.program = *program;
+    }
+
+    pub fn set_tape(&mut self, drive: f32, saturation: f32, width: f32) {
+        self.tape.set_tape(drive, saturation, width);
+    }
+
+    pub fn set_diode(&mut self, diode_type: DiodeType, capacitance: f32) {
+        self.diode.set_diode(diode_type, capacitance);
+    }
+
+    pub fn set_tube(&mut self, plate_voltage: f32, bias: f32) {
+        self.tube.set_tube(plate_voltage, bias);
+    }
+
+    /// Exchange the NEURAL model with another one, so that the previous model is not deallocated
+    /// on the audio thread
+    pub fn swap_neural_model(&mut self, model: &mut Option<NeuralModel>) {
+        std::mem::swap(&mut self.neural.model, model);
+        self.neural.reset();
+    }
+
+    /// Rate the NEURAL model was trained at, if one is loaded
+    pub fn neural_sample_rate(&self) -> Option<f32> {
+        self.neural.model.as_ref().map(NeuralModel::sample_rate)
+    }
+
+    pub fn set_adaa_order(&mut self, order: AdaaOrder) {
+        self.adaa_order = order;
+        self.soft_clipping.set_order(order);
+        self.hard_clipping.set_order(order);
+    }
+
+    /// Group delay of the bank, in samples at the rate it runs at. The second type counts even
+    /// while its weight is 0, so that automating the morph doesn't move the signal
+    pub fn delay(&self) -> f32 {
+        if self.distortion_type.uses_adaa() || self.distortion_type_b.uses_adaa() {
+            self.adaa_order.delay()
+        } else {
+            0.0
+        }
+    }
+
+    /// Kernel the input of a type goes through to be delayed like the other one
+    fn compensation(&self, distortion_type: DistortionType) -> AdaaOrder {
+        if distortion_type.uses_adaa() || self.delay() == 0.0 {
+            AdaaOrder::Off
+        } else {
+            self.adaa_order
+        }
+    }
+}
+
+impl DistortionBank {
+    /// Run both types on copies of the same samples and crossfade them. The processors are
+    /// skipped while their weight stays at 0, and start from a cleared state when it rises again.
+    fn process_morph(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        let (start, end) = (self.morph, self.morph_target);
+        self.morph = end;
+        let type_b = self.distortion_type_b;
+        let (order_a, order_b) = (
+            self.compensation(self.distortion_type),
+            self.compensation(type_b),
+        );
+        if type_b == self.distortion_type || (start == 0.0 && end == 0.0) {
+            self.delay_a.process(order_a, samples);
+            self.selected().process(pre_gain, post_gain, samples);
+            return;
+        }
+        if start == 0.0 {
+            self.delay_b.reset();
+            self.processor(type_b).reset();
+        }
+        if start == 1.0 && end == 1.0 {
+            self.delay_b.process(order_b, samples);
+            self.processor(type_b).process(pre_gain, post_gain, samples);
+            return;
+        }
+        if start == 1.0 {
+            self.delay_a.reset();
+            self.selected().reset();
+        }
+
+        let step = (end - start) / samples.len() as f32;
+        let mut buffer = [0.0; MORPH_CHUNK_SIZE];
+        for (chunk_index, chunk) in samples.chunks_mut(MORPH_CHUNK_SIZE).enumerate() {
+            let other = &mut buffer[..chunk.len()];
+            other.copy_from_slice(chunk);
+            self.delay_a.process(order_a, chunk);
+            self.delay_b.process(order_b, other);
+            self.selected().process(pre_gain, post_gain, chunk);
+            self.processor(type_b).process(pre_gain, post_gain, other);
+            for (index, (a, b)) in chunk.iter_mut().zip(other.iter()).enumerate() {
+                let morph = start + step * (chunk_index * MORPH_CHUNK_SIZE + index + 1) as f32;
+                *a += (b - *a) * morph;
+            }
         }
     }
 }
 
-pub fn soft_clipping(pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
-    let top: f32 = 1.0;
-    let bottom: f32 = -1.0;
-    for sample in samples {
-        *sample *= pre_gain;
-        if *sample <= bottom {
-            *sample = -0.66666666;
-        } else if *sample >= top {
-            *sample = 0.66666666;
+impl DistortionProcessor for DistortionBank {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        if self.bias == 0.0 {
+            self.process_morph(pre_gain, post_gain, samples);
         } else {
-            *sample = *sample - (sample.powf(3.0) / 3.0);
+            for sample in samples.iter_mut() {
+                *sample = *sample * pre_gain + self.bias;
+            }
+            self.process_morph(1.0, post_gain, samples);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.delay_a.reset();
+        self.delay_b.reset();
+        for processor in self.processors() {
+            processor.reset();
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            for processor in self.processors() {
+                processor.set_sample_rate(sample_rate);
+            }
+        }
+    }
+}
+
+pub const MAX_STAGES: usize = 4;
+
+//
+// Serial distortion stages sharing the same (oversampled) block, so that the signal is only
+// filtered once. The first stage is always enabled and driven by the pre gain, the following ones
+// by their own drive. The post gain is applied after the last enabled stage.
+//
+pub struct DistortionChain {
+    stages: [DistortionBank; MAX_STAGES],
+    enabled: [bool; MAX_STAGES],
+    drives: [f32; MAX_STAGES],
+}
+
+impl DistortionChain {
+    /// See `DistortionBank::new` for the seed
+    pub fn new(sample_rate: f32, seed: u32) -> Self {
+        let mut enabled = [false; MAX_STAGES];
+        enabled[0] = true;
+        Self {
+            stages: std::array::from_fn(|stage| {
+                DistortionBank::new(sample_rate, seed * MAX_STAGES as u32 + stage as u32)
+            }),
+            enabled,
+            drives: [1.0; MAX_STAGES],
+        }
+    }
+
+    /// A stage being enabled starts from a cleared state. The drive of the first stage is ignored.
+    pub fn set_stage(
+        &mut self,
+        index: usize,
+        distortion_type: DistortionType,
+        enabled: bool,
+        drive: f32,
+    ) {
+        let enabled = enabled || index == 0;
+        if enabled && !self.enabled[index] {
+            self.stages[index].reset();
+        }
+        self.enabled[index] = enabled;
+        self.drives[index] = drive;
+        self.stages[index].set_type(distortion_type);
+    }
+
+    /// All the stages, enabled or not
+    pub fn stages_mut(&mut self) -> impl Iterator<Item = &mut DistortionBank> {
+        self.stages.iter_mut()
+    }
+
+    /// Second type blended into the first stage, see `DistortionBank::set_morph`
+    pub fn set_morph(&mut self, distortion_type: DistortionType, morph: f32) {
+        self.stages[0].set_morph(distortion_type, morph);
+    }
+
+    /// Rate the NEURAL model was trained at, if one is loaded
+    pub fn neural_sample_rate(&self) -> Option<f32> {
+        self.stages[0].neural_sample_rate()
+    }
+
+    /// Number of enabled stages delayed by ADAA, see `DistortionBank::delay`
+    pub fn adaa_stages(&self) -> usize {
+        self.stages
+            .iter()
+            .zip(self.enabled)
+            .filter(|(stage, enabled)| *enabled && stage.delay() > 0.0)
+            .count()
+    }
+}
+
+impl DistortionProcessor for DistortionChain {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        let last = self
+            .enabled
+            .iter()
+            .rposition(|&enabled| enabled)
+            .unwrap_or(0);
+        for index in 0..=last {
+            if self.enabled[index] {
+                let pre_gain = if index == 0 {
+                    pre_gain
+                } else {
+                    self.drives[index]
+                };
+                let post_gain = if index == last { post_gain } else { 1.0 };
+                self.stages[index].process(pre_gain, post_gain, samples);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for stage in &mut self.stages {
+            stage.reset();
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        for stage in &mut self.stages {
+            stage.set_sample_rate(sample_rate);
+        }
+    }
+}
+
+pub const MAX_BANDS: usize = 3;
+
+/// Samples the bands are split into at once, on the stack
+const BAND_CHUNK_SIZE: usize = 64;
+
+/// Number of frequency bands distorted separately
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum BandSplit {
+    #[name = "Off"]
+    Off,
+    #[name = "2 bands"]
+    Two,
+    #[name = "3 bands"]
+    Three,
+}
+
+impl BandSplit {
+    pub fn bands(self) -> usize {
+        match self {
+            Self::Off => 1,
+            Self::Two => 2,
+            Self::Three => 3,
+        }
+    }
+}
+
+/// How a band is distorted and mixed back, besides its chain
+#[derive(Debug, Clone, Copy)]
+pub struct BandSettings {
+    /// Gain going into the band's chain, on top of the pre gain
+    pub drive: f32,
+    pub dry_gain: f32,
+    pub wet_gain: f32,
+    /// False when the band is muted, or when another one is soloed
+    pub audible: bool,
+}
+
+impl Default for BandSettings {
+    fn default() -> Self {
+        Self {
+            drive: 1.0,
+            dry_gain: 0.0,
+            wet_gain: 1.0,
+            audible: true,
+        }
+    }
+}
+
+//
+// Splits the signal with Linkwitz-Riley crossovers and runs one chain per band before summing
+// them back. In 3 band mode the low band goes through the allpass response of the high crossover,
+// so that the sum of the three bands keeps a flat magnitude. The bands with fewer ADAA stages than
+// the others, and the dry bands, go through as many `AdaaDelay` as they miss, so that they are
+// summed in phase. Without split, the first chain processes the whole signal and the band settings
+// are ignored.
+//
+pub struct MultibandDistortion {
+    bands: [DistortionChain; MAX_BANDS],
+    settings: [BandSettings; MAX_BANDS],
+    /// Gains of the bands at the end of the previous block, ramped towards 0 when muted
+    gains: [f32; MAX_BANDS],
+    adaa_order: AdaaOrder,
+    dry_delays: [[AdaaDelay; MAX_STAGES]; MAX_BANDS],
+    wet_delays: [[AdaaDelay; MAX_STAGES]; MAX_BANDS],
+    split: BandSplit,
+    /// Between the low and mid bands, then between the mid and high ones
+    crossovers: [Crossover; 2],
+    low_allpass: BiquadFilter,
+    frequencies: [f32; 2],
+    sample_rate: f32,
+}
+
+impl MultibandDistortion {
+    /// See `DistortionBank::new` for the seed
+    pub fn new(sample_rate: f32, seed: u32) -> Self {
+        let frequencies = [200.0, 2000.0];
+        Self {
+            bands: std::array::from_fn(|band| {
+                DistortionChain::new(sample_rate, seed * MAX_BANDS as u32 + band as u32)
+            }),
+            settings: [BandSettings::default(); MAX_BANDS],
+            gains: [1.0; MAX_BANDS],
+            adaa_order: AdaaOrder::Off,
+            dry_delays: std::array::from_fn(|_| std::array::from_fn(|_| AdaaDelay::new())),
+            wet_delays: std::array::from_fn(|_| std::array::from_fn(|_| AdaaDelay::new())),
+            split: BandSplit::Off,
+            crossovers: frequencies.map(|frequency| Crossover::new(frequency, sample_rate)),
+            low_allpass: BiquadFilter::with_parameters(
+                frequencies[1],
+                sample_rate,
+                BUTTERWORTH_Q,
+                0.0,
+                FilterType::ALLPASS,
+            ),
+            frequencies,
+            sample_rate,
+        }
+    }
+
+    /// The frequencies are sorted and kept below Nyquist. Changing the split clears the bands
+    pub fn set_split(&mut self, split: BandSplit, low_frequency: f32, high_frequency: f32) {
+        if split != self.split {
+            self.split = split;
+            self.reset();
+        }
+        let frequencies = [
+            low_frequency.min(high_frequency),
+            low_frequency.max(high_frequency),
+        ];
+        if frequencies != self.frequencies {
+            self.frequencies = frequencies;
+            self.update_crossovers();
+        }
+    }
+
+    pub fn set_band(&mut self, index: usize, settings: BandSettings) {
+        self.settings[index] = settings;
+    }
+
+    /// All the bands, split or not
+    pub fn bands_mut(&mut self) -> impl Iterator<Item = &mut DistortionChain> {
+        self.bands.iter_mut()
+    }
+
+    /// All the stages of all the bands
+    pub fn stages_mut(&mut self) -> impl Iterator<Item = &mut DistortionBank> {
+        self.bands.iter_mut().flat_map(DistortionChain::stages_mut)
+    }
+
+    /// Rate the NEURAL model was trained at, if one is loaded
+    pub fn neural_sample_rate(&self) -> Option<f32> {
+        self.bands[0].neural_sample_rate()
+    }
+
+    /// Shared by all the stages, and by the delays aligning the bands
+    pub fn set_adaa_order(&mut self, order: AdaaOrder) {
+        self.adaa_order = order;
+        for stage in self.stages_mut() {
+            stage.set_adaa_order(order);
+        }
+    }
+
+    fn update_crossovers(&mut self) {
+        let nyquist = self.sample_rate * 0.45;
+        let [low, high] = self.frequencies.map(|frequency| frequency.min(nyquist));
+        self.crossovers[0].set_frequency(low, self.sample_rate);
+        self.crossovers[1].set_frequency(high, self.sample_rate);
+        self.low_allpass.update_coefficients(
+            high,
+            self.sample_rate,
+            BUTTERWORTH_Q,
+            0.0,
+            FilterType::ALLPASS,
+        );
+    }
+}
+
+impl DistortionProcessor for MultibandDistortion {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        let bands = self.split.bands();
+        if bands == 1 {
+            self.bands[0].process(pre_gain, post_gain, samples);
+            return;
+        }
+
+        let adaa_stages: [usize; MAX_BANDS] =
+            std::array::from_fn(|band| self.bands[band].adaa_stages());
+        let aligned_stages = adaa_stages[..bands].iter().copied().max().unwrap_or(0);
+        // Like the morph, the mute and solo gains are ramped over the whole block
+        let targets = self
+            .settings
+            .map(|settings| if settings.audible { 1.0 } else { 0.0 });
+        let starts = std::mem::replace(&mut self.gains, targets);
+        let steps: [f32; MAX_BANDS] =
+            std::array::from_fn(|band| (targets[band] - starts[band]) / samples.len() as f32);
+
+        let mut dry = [[0.0; BAND_CHUNK_SIZE]; MAX_BANDS];
+        let mut wet = [[0.0; BAND_CHUNK_SIZE]; MAX_BANDS];
+        for (chunk_index, chunk) in samples.chunks_mut(BAND_CHUNK_SIZE).enumerate() {
+            for (index, &sample) in chunk.iter().enumerate() {
+                let (mut low, high) = self.crossovers[0].split(sample);
+                if bands == 2 {
+                    dry[0][index] = low;
+                    dry[1][index] = high;
+                } else {
+                    let (mid, high) = self.crossovers[1].split(high);
+                    dry[0][index] = self.low_allpass.filter(&mut low);
+                    dry[1][index] = mid;
+                    dry[2][index] = high;
+                }
+            }
+
+            chunk.fill(0.0);
+            for band in 0..bands {
+                let settings = self.settings[band];
+                let (dry, wet) = (&mut dry[band][..chunk.len()], &mut wet[band][..chunk.len()]);
+                wet.copy_from_slice(dry);
+                // Muted bands keep running, so that they are up to date once their gain rises
+                self.bands[band].process(pre_gain * settings.drive, post_gain, wet);
+                for delay in &mut self.wet_delays[band][adaa_stages[band]..aligned_stages] {
+                    delay.process(self.adaa_order, wet);
+                }
+                for delay in &mut self.dry_delays[band][..aligned_stages] {
+                    delay.process(self.adaa_order, dry);
+                }
+                if starts[band] == 0.0 && targets[band] == 0.0 {
+                    continue;
+                }
+                for (index, (sample, (dry, wet))) in
+                    chunk.iter_mut().zip(dry.iter().zip(wet.iter())).enumerate()
+                {
+                    let gain = starts[band]
+                        + steps[band] * (chunk_index * BAND_CHUNK_SIZE + index + 1) as f32;
+                    *sample += (dry * settings.dry_gain + wet * settings.wet_gain) * gain;
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for band in &mut self.bands {
+            band.reset();
+        }
+        for delay in self
+            .dry_delays
+            .iter_mut()
+            .chain(&mut self.wet_delays)
+            .flatten()
+        {
+            delay.reset();
+        }
+        for crossover in &mut self.crossovers {
+            crossover.reset();
+        }
+        self.low_allpass.reset();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        for band in &mut self.bands {
+            band.set_sample_rate(sample_rate);
+        }
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            self.update_crossovers();
+        }
+    }
+}
+
+/// Memoryless distortion applying a transfer curve to every sample
+pub struct Waveshaper {
+    curve: fn(f32) -> f32,
+}
+
+impl Waveshaper {
+    pub fn new(curve: fn(f32) -> f32) -> Self {
+        Self { curve }
+    }
+}
+
+impl DistortionProcessor for Waveshaper {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        for sample in samples {
+            *sample = (self.curve)(*sample * pre_gain) * post_gain;
+        }
+    }
+}
+
+// The sigmoids below have a unit slope at the origin and saturate at +/-1, like hard_clipping,
+// so that the same pre gain drives them equally hard
+
+pub fn tanh_saturation(x: f32) -> f32 {
+    x.tanh()
+}
+
+pub fn atan_saturation(x: f32) -> f32 {
+    (x * FRAC_PI_2).atan() / FRAC_PI_2
+}
+
+pub fn algebraic_saturation(x: f32) -> f32 {
+    x / (1.0 + x * x).sqrt()
+}
+
+//
+// Clipper morphing from soft_clipping (knee = 0) into hard_clipping (knee = 1): linear up to the
+// knee, then a cubic segment of width 1 - knee bending into the ceiling knee + 2 / 3 (1 - knee).
+// The curve and its slope stay continuous for every knee, and both ends match SOFT and HARD
+// exactly
+//
+pub struct KneeClipper {
+    knee: f32,
+}
+
+impl KneeClipper {
+    pub fn new() -> Self {
+        Self { knee: 0.5 }
+    }
+
+    pub fn set_knee(&mut self, knee: f32) {
+        self.knee = knee.clamp(0.0, 1.0);
+    }
+}
+
+impl DistortionProcessor for KneeClipper {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        for sample in samples {
+            *sample = knee_clipping(*sample * pre_gain, self.knee) * post_gain;
+        }
+    }
+}
+
+pub fn knee_clipping(x: f32, knee: f32) -> f32 {
+    let width = 1.0 - knee;
+    let magnitude = x.abs();
+    if magnitude <= knee {
+        x
+    } else if magnitude >= 1.0 {
+        x.signum() * (knee + width * 2.0 / 3.0)
+    } else {
+        let u = (magnitude - knee) / width;
+        x.signum() * (knee + width * (u - u * u * u / 3.0))
+    }
+}
+
+//
+// Rectifier scaling the negative half-wave by 1 - 2 amount: the signal goes through unchanged at 0,
+// is half-wave rectified max(x, 0) at 0.5 and full-wave rectified |x| at 1. The output is then
+// clipped at +/-1 like HARD. The large DC offset is left to the DC blocker.
+//
+pub struct Rectifier {
+    negative_gain: f32,
+}
+
+impl Rectifier {
+    pub fn new(amount: f32) -> Self {
+        let mut rectifier = Self { negative_gain: 1.0 };
+        rectifier.set_amount(amount);
+        rectifier
+    }
+
+    pub fn set_amount(&mut self, amount: f32) {
+        self.negative_gain = 1.0 - 2.0 * amount.clamp(0.0, 1.0);
+    }
+}
+
+impl DistortionProcessor for Rectifier {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        for sample in samples {
+            *sample = rectify(*sample * pre_gain, self.negative_gain) * post_gain;
+        }
+    }
+}
+
+pub fn rectify(x: f32, negative_gain: f32) -> f32 {
+    let y = if x < 0.0 { x * negative_gain } else { x };
+    y.clamp(-1.0, 1.0)
+}
+
+//
+// Harmonic generator: sum of the Chebyshev polynomials T1..T8, weighted by the harmonic amounts.
+// Since T_n(cos(t)) = cos(n t), a full scale sine comes out with exactly the chosen spectrum. The
+// input is clipped at +/-1, outside of which the polynomials blow up.
+//
+pub const HARMONICS_COUNT: usize = 7;
+
+pub struct HarmonicGenerator {
+    amounts: [f32; HARMONICS_COUNT],
+}
+
+impl HarmonicGenerator {
+    pub fn new() -> Self {
+        Self {
+            amounts: [0.0; HARMONICS_COUNT],
+        }
+    }
+
+    pub fn set_amounts(&mut self, amounts: [f32; HARMONICS_COUNT]) {
+        self.amounts = amounts;
+    }
+}
+
+impl DistortionProcessor for HarmonicGenerator {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        for sample in samples {
+            *sample = chebyshev_harmonics(*sample * pre_gain, &self.amounts) * post_gain;
+        }
+    }
+}
+
+/// T1(x) + sum of amounts[n - 2] * Tn(x) for n in 2..=8, using T(n+1) = 2 x Tn - T(n-1)
+pub fn chebyshev_harmonics(x: f32, amounts: &[f32; HARMONICS_COUNT]) -> f32 {
+    let x = x.clamp(-1.0, 1.0);
+    let (mut previous, mut current) = (1.0, x);
+    let mut y = x;
+    for amount in amounts {
+        (previous, current) = (current, 2.0 * x * current - previous);
+        y += amount * current;
+    }
+    y
+}
+
+//
+// User drawn curve: the spline going through the control points is sampled into a lookup table,
+// linearly interpolated at run time. The table spans [-1, 1], hotter inputs are clipped to the
+// curve's ends.
+//
+pub const CUSTOM_CURVE_SIZE: usize = 1025;
+
+pub struct CustomCurve {
+    table: [f32; CUSTOM_CURVE_SIZE],
+}
+
+impl CustomCurve {
+    pub fn new() -> Self {
+        let mut curve = Self {
+            table: [0.0; CUSTOM_CURVE_SIZE],
+        };
+        curve.set_points(&default_custom_curve());
+        curve
+    }
+
+    /// Does not allocate, so that the table can be rebuilt on the audio thread
+    pub fn set_points(&mut self, points: &[(f32, f32)]) {
+        let step = 2.0 / (CUSTOM_CURVE_SIZE - 1) as f32;
+        for (index, value) in self.table.iter_mut().enumerate() {
+            *value = spline(points, index as f32 * step - 1.0);
+        }
+    }
+}
+
+impl DistortionProcessor for CustomCurve {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        let scale = (CUSTOM_CURVE_SIZE - 1) as f32 / 2.0;
+        for sample in samples {
+            let position = ((*sample * pre_gain).clamp(-1.0, 1.0) + 1.0) * scale;
+            let index = (position as usize).min(CUSTOM_CURVE_SIZE - 2);
+            let fraction = position - index as f32;
+            let (y0, y1) = (self.table[index], self.table[index + 1]);
+            *sample = (y0 + (y1 - y0) * fraction) * post_gain;
         }
-        *sample *= post_gain;
     }
 }
 
-pub fn hard_clipping(pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
-    let top: f32 = 1.0;
-    let bottom: f32 = -1.0;
-    for sample in samples {
-        *sample *= pre_gain;
-        if *sample <= bottom {
-            *sample = -1.0;
-        } else if *sample >= top {
-            *sample = 1.0;
+/// A gentle saturation, the starting point of the CUSTOM curve
+pub fn default_custom_curve() -> Vec<(f32, f32)> {
+    vec![
+        (-1.0, -1.0),
+        (-0.5, -0.75),
+        (0.0, 0.0),
+        (0.5, 0.75),
+        (1.0, 1.0),
+    ]
+}
+
+/// Bring points from a restored state back to what the editor produces: finite, within [-1, 1],
+/// sorted by x and with the end points at -1 and 1. Too few points give the default curve.
+pub fn sanitize_custom_curve(points: &mut Vec<(f32, f32)>) {
+    points.retain(|(x, y)| x.is_finite() && y.is_finite());
+    if points.len() < 2 {
+        *points = default_custom_curve();
+        return;
+    }
+    for (x, y) in points.iter_mut() {
+        *x = x.clamp(-1.0, 1.0);
+        *y = y.clamp(-1.0, 1.0);
+    }
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let last = points.len() - 1;
+    points[0].0 = -1.0;
+    points[last].0 = 1.0;
+}
+
+/// Monotone cubic (Fritsch-Butland) interpolation of points sorted by x: the curve never
+/// overshoots the control points, so that a monotonic set of points gives a monotonic curve.
+/// Outside of the points the curve is flat.
+pub fn spline(points: &[(f32, f32)], x: f32) -> f32 {
+    let last = match points.len() {
+        0 => return x,
+        1 => return points[0].1,
+        len => len - 1,
+    };
+    let x = x.clamp(points[0].0, points[last].0);
+    let k = points[..last]
+        .iter()
+        .rposition(|point| point.0 <= x)
+        .unwrap_or(0);
+    let ((x0, y0), (x1, y1)) = (points[k], points[k + 1]);
+    let h = x1 - x0;
+    if h <= 0.0 {
+        return y1;
+    }
+    let t = (x - x0) / h;
+    let (m0, m1) = (spline_tangent(points, k), spline_tangent(points, k + 1));
+    let (t2, t3) = (t * t, t * t * t);
+    y0 * (2.0 * t3 - 3.0 * t2 + 1.0)
+        + h * m0 * (t3 - 2.0 * t2 + t)
+        + y1 * (3.0 * t2 - 2.0 * t3)
+        + h * m1 * (t3 - t2)
+}
+
+fn spline_secant(points: &[(f32, f32)], k: usize) -> f32 {
+    let h = points[k + 1].0 - points[k].0;
+    if h > 0.0 {
+        (points[k + 1].1 - points[k].1) / h
+    } else {
+        0.0
+    }
+}
+
+/// Harmonic mean of the neighbouring secants, flat at local extrema
+fn spline_tangent(points: &[(f32, f32)], k: usize) -> f32 {
+    let last = points.len() - 1;
+    if k == 0 {
+        spline_secant(points, 0)
+    } else if k == last {
+        spline_secant(points, last - 1)
+    } else {
+        let (d0, d1) = (spline_secant(points, k - 1), spline_secant(points, k));
+        if d0 * d1 <= 0.0 {
+            0.0
+        } else {
+            2.0 * d0 * d1 / (d0 + d1)
+        }
+    }
+}
+
+//
+// Transfer function typed by the user, see `expression.rs`. Non finite results, like a division
+// by zero, are muted.
+//
+pub struct ExpressionShaper {
+    program: Program,
+}
+
+impl ExpressionShaper {
+    pub fn new() -> Self {
+        Self {
+            program: Program::default(),
+        }
+    }
+}
+
+impl DistortionProcessor for ExpressionShaper {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        for sample in samples {
+            let y = self.program.eval(*sample * pre_gain);
+            *sample = if y.is_finite() { y * post_gain } else { 0.0 };
+        }
+    }
+}
+
+//
+// Tape magnetization following the Jiles-Atherton hysteresis model: the magnetization M lags
+// behind the field H (the input) along a loop whose shape depends on the magnetization history.
+// dM/dt = dH/dt * dM/dH is integrated with a fourth order Runge-Kutta step per sample, the field
+// being linearly interpolated within the sample, which is why this type benefits from
+// oversampling. The output is M / Ms, the saturation magnetization mapping to full scale.
+//
+// Drive narrows the anhysteretic curve (a), saturation lowers Ms and width widens the loop by
+// lowering the reversible magnetization ratio (c).
+//
+pub struct Hysteresis {
+    /// Saturation magnetization
+    ms: f64,
+    /// Anhysteretic magnetization shape
+    a: f64,
+    /// Mean field parameter
+    alpha: f64,
+    /// Coercivity, the loop half width
+    k: f64,
+    /// Ratio of reversible magnetization
+    c: f64,
+    m: f64,
+    h: f64,
+}
+
+/// Coercivity and mean field coupling of the tape, from measurements on real tape
+const TAPE_COERCIVITY: f64 = 0.47875;
+const TAPE_MEAN_FIELD: f64 = 1.6e-3;
+
+impl Hysteresis {
+    pub fn new() -> Self {
+        let mut hysteresis = Self {
+            ms: 1.0,
+            a: 1.0,
+            alpha: TAPE_MEAN_FIELD,
+            k: TAPE_COERCIVITY,
+            c: 0.5,
+            m: 0.0,
+            h: 0.0,
+        };
+        hysteresis.set_tape(0.5, 0.5, 0.5);
+        hysteresis
+    }
+
+    /// All three in [0, 1]
+    pub fn set_tape(&mut self, drive: f32, saturation: f32, width: f32) {
+        let (drive, saturation, width) = (
+            drive.clamp(0.0, 1.0) as f64,
+            saturation.clamp(0.0, 1.0) as f64,
+            width.clamp(0.0, 1.0) as f64,
+        );
+        self.ms = 0.5 + 1.5 * (1.0 - saturation);
+        self.a = self.ms / (0.5 + 5.5 * drive);
+        // Kept inside (0, 1), the domain of the model
+        self.c = 0.01 + 0.98 * (1.0 - width).sqrt();
+    }
+
+    /// dM/dH for a field moving in the direction delta
+    fn slope(&self, m: f64, h: f64, delta: f64) -> f64 {
+        let q = (h + self.alpha * m) / self.a;
+        let (langevin, langevin_slope) = langevin(q);
+        let m_diff = self.ms * langevin - m;
+        // The irreversible magnetization only moves towards the anhysteretic curve
+        let delta_m = if delta * m_diff > 0.0 { 1.0 } else { 0.0 };
+        let denominator = (1.0 - self.c) * delta * self.k - self.alpha * m_diff;
+        let irreversible = if denominator.abs() > f64::EPSILON {
+            (1.0 - self.c) * delta_m * m_diff / denominator
+        } else {
+            0.0
+        };
+        let reversible = self.ms * self.c / self.a * langevin_slope;
+        let feedback = 1.0 - self.ms * self.c * self.alpha / self.a * langevin_slope;
+        (irreversible + reversible) / feedback
+    }
+}
+
+impl DistortionProcessor for Hysteresis {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        for sample in samples {
+            let h = (*sample * pre_gain) as f64;
+            let dh = h - self.h;
+            let delta = if dh >= 0.0 { 1.0 } else { -1.0 };
+            let h_mid = self.h + dh / 2.0;
+            let k1 = dh * self.slope(self.m, self.h, delta);
+            let k2 = dh * self.slope(self.m + k1 / 2.0, h_mid, delta);
+            let k3 = dh * self.slope(self.m + k2 / 2.0, h_mid, delta);
+            let k4 = dh * self.slope(self.m + k3, h, delta);
+            self.m += (k1 + 2.0 * k2 + 2.0 * k3 + k4) / 6.0;
+            if !self.m.is_finite() {
+                self.m = 0.0;
+            }
+            self.h = h;
+            *sample = (self.m / self.ms) as f32 * post_gain;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.m = 0.0;
+        self.h = 0.0;
+    }
+}
+
+/// The Langevin function coth(q) - 1 / q and its derivative, using their Taylor expansions
+/// around 0 where the closed forms cancel out
+fn langevin(q: f64) -> (f64, f64) {
+    if q.abs() < 1e-4 {
+        (q / 3.0, 1.0 / 3.0)
+    } else {
+        let sinh = q.sinh();
+        (
+            1.0 / q.tanh() - 1.0 / q,
+            1.0 / (q * q) - 1.0 / (sinh * sinh),
+        )
+    }
+}
+
+//
+// Diode clipper circuit: a resistor feeding a capacitor shunted by two antiparallel diodes. The
+// capacitor voltage V follows
+//   C dV/dt = (Vin - V) / R - 2 Is sinh(V / (n Vt))
+// discretized with the trapezoidal rule and solved with Newton-Raphson iterations every sample.
+// Unlike a static curve, the RC lowpass makes the clipping depend on the frequency: the higher the
+// frequency, the less the signal reaches the diodes. The signal is scaled so that full scale
+// matches the diodes' forward voltage (at 1 mA).
+//
+pub struct DiodeClipper {
+    sample_rate: f64,
+    /// Saturation current
+    is: f64,
+    /// Ideality factor times the thermal voltage
+    nvt: f64,
+    forward_voltage: f64,
+    capacitance: f64,
+    /// Capacitor voltage
+    v: f64,
+    /// Previous input voltage
+    vin: f64,
+}
+
+const DIODE_RESISTANCE: f64 = 2.2e3;
+const THERMAL_VOLTAGE: f64 = 25.85e-3;
+const NEWTON_MAX_ITERATIONS: usize = 16;
+/// Enough bisections to narrow a bracket of hundreds of volts down to the tolerance
+const DIODE_MAX_ITERATIONS: usize = 48;
+const NEWTON_TOLERANCE: f64 = 1e-9;
+
+impl DiodeClipper {
+    pub fn new(sample_rate: f32) -> Self {
+        let mut clipper = Self {
+            sample_rate: sample_rate as f64,
+            is: 0.0,
+            nvt: 0.0,
+            forward_voltage: 1.0,
+            capacitance: 10e-9,
+            v: 0.0,
+            vin: 0.0,
+        };
+        clipper.set_diode(DiodeType::Silicon, 10.0);
+        clipper
+    }
+
+    /// Capacitance in nF
+    pub fn set_diode(&mut self, diode_type: DiodeType, capacitance: f32) {
+        let (is, n) = diode_type.shockley();
+        self.is = is;
+        self.nvt = n * THERMAL_VOLTAGE;
+        self.forward_voltage = self.nvt * (1e-3 / is).ln();
+        self.capacitance = capacitance as f64 * 1e-9;
+    }
+
+    /// dV/dt
+    fn derivative(&self, v: f64, vin: f64) -> f64 {
+        ((vin - v) / DIODE_RESISTANCE - 2.0 * self.is * (v / self.nvt).sinh()) / self.capacitance
+    }
+}
+
+impl DistortionProcessor for DiodeClipper {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        let half_step = 0.5 / self.sample_rate;
+        for sample in samples {
+            let vin = (*sample * pre_gain) as f64 * self.forward_voltage;
+            // Solve g(v) = v - v[n-1] - T / 2 (f(v[n-1]) + f(v)) = 0, g being increasing
+            let known = self.v + half_step * self.derivative(self.v, self.vin);
+            // The diodes only pull the solution towards 0: it lies between 0 and the solution
+            // without them
+            let linear = half_step / (DIODE_RESISTANCE * self.capacitance);
+            let without_diodes = (known + linear * vin) / (1.0 + linear);
+            let (mut low, mut high) = (without_diodes.min(0.0), without_diodes.max(0.0));
+            let mut v = self.v.clamp(low, high);
+            let mut previous_step = high - low;
+            for _ in 0..DIODE_MAX_ITERATIONS {
+                let g = v - known - half_step * self.derivative(v, vin);
+                if g > 0.0 {
+                    high = v;
+                } else {
+                    low = v;
+                }
+                let slope = 1.0
+                    + half_step / self.capacitance
+                        * (1.0 / DIODE_RESISTANCE
+                            + 2.0 * self.is / self.nvt * (v / self.nvt).cosh());
+                // Far from the solution the exponential makes the tangent a poor guess: the
+                // steps leaving the bracket or not converging fast enough are replaced by
+                // bisections
+                let next = v - g / slope;
+                let next = if next > low && next < high && (next - v).abs() < previous_step / 2.0 {
+                    next
+                } else {
+                    (low + high) / 2.0
+                };
+                previous_step = (next - v).abs();
+                v = next;
+                if previous_step < NEWTON_TOLERANCE {
+                    break;
+                }
+            }
+            self.v = v;
+            self.vin = vin;
+            *sample = (v / self.forward_voltage) as f32 * post_gain;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.v = 0.0;
+        self.vin = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate as f64;
+    }
+}
+
+//
+// Common cathode 12AX7 triode stage, the plate current following Koren's equations. The plate
+// voltage is found on the load line Vp = B+ - Rl Ip with Newton-Raphson iterations. Positive grid
+// voltages are softly limited, like with grid current. The cathode bias sags with the envelope of
+// the plate current, so that transients push the bias further negative: the stage compresses and
+// its asymmetry moves dynamically.
+//
+// Full scale is a grid swing of TUBE_GRID_SWING volts. The output is the plate voltage swing,
+// normalized by a quarter of the supply voltage, and not inverted.
+//
+pub struct Triode {
+    sample_rate: f32,
+    supply: f64,
+    bias: f64,
+    /// Operating point without signal
+    quiescent_voltage: f64,
+    quiescent_current: f64,
+    plate_voltage: f64,
+    /// Plate current envelope
+    envelope: f64,
+    attack: f64,
+    release: f64,
+}
+
+// 12AX7 Koren parameters
+const KOREN_MU: f64 = 100.0;
+const KOREN_EX: f64 = 1.4;
+const KOREN_KG1: f64 = 1060.0;
+const KOREN_KP: f64 = 600.0;
+const KOREN_KVB: f64 = 300.0;
+
+const TUBE_LOAD_RESISTANCE: f64 = 100e3;
+/// Cathode resistance converting the plate current envelope into a bias shift
+const TUBE_SAG_RESISTANCE: f64 = 1.5e3;
+const TUBE_GRID_SWING: f64 = 2.0;
+const TUBE_ATTACK_MS: f32 = 5.0;
+const TUBE_RELEASE_MS: f32 = 100.0;
+
+impl Triode {
+    pub fn new(sample_rate: f32) -> Self {
+        let mut triode = Self {
+            sample_rate,
+            supply: 0.0,
+            bias: 0.0,
+            quiescent_voltage: 0.0,
+            quiescent_current: 0.0,
+            plate_voltage: 0.0,
+            envelope: 0.0,
+            attack: 0.0,
+            release: 0.0,
+        };
+        triode.set_sample_rate(sample_rate);
+        triode.set_tube(250.0, -1.5);
+        triode.reset();
+        triode
+    }
+
+    /// Supply voltage and grid bias, in volts
+    pub fn set_tube(&mut self, plate_voltage: f32, bias: f32) {
+        let (supply, bias) = (plate_voltage as f64, bias as f64);
+        if supply != self.supply || bias != self.bias {
+            self.supply = supply;
+            self.bias = bias;
+            self.quiescent_voltage = self.load_line(bias, supply);
+            self.quiescent_current = (supply - self.quiescent_voltage) / TUBE_LOAD_RESISTANCE;
+        }
+    }
+
+    /// Plate voltage for a grid voltage, starting the search from `guess`
+    fn load_line(&self, grid_voltage: f64, guess: f64) -> f64 {
+        let mut plate_voltage = guess;
+        for _ in 0..NEWTON_MAX_ITERATIONS {
+            let h = plate_voltage - self.supply
+                + TUBE_LOAD_RESISTANCE * koren_current(grid_voltage, plate_voltage);
+            let delta = 1e-3;
+            let slope = 1.0
+                + TUBE_LOAD_RESISTANCE
+                    * (koren_current(grid_voltage, plate_voltage + delta)
+                        - koren_current(grid_voltage, plate_voltage))
+                    / delta;
+            let step = h / slope;
+            plate_voltage = (plate_voltage - step).clamp(0.0, self.supply);
+            if step.abs() < 1e-6 {
+                break;
+            }
+        }
+        plate_voltage
+    }
+}
+
+impl DistortionProcessor for Triode {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        let swing = self.supply / 4.0;
+        for sample in samples {
+            let sag = TUBE_SAG_RESISTANCE * (self.envelope - self.quiescent_current);
+            let grid_voltage =
+                grid_conduction(self.bias + (*sample * pre_gain) as f64 * TUBE_GRID_SWING) - sag;
+            self.plate_voltage = self.load_line(grid_voltage, self.plate_voltage);
+
+            let current = (self.supply - self.plate_voltage) / TUBE_LOAD_RESISTANCE;
+            let coefficient = if current > self.envelope {
+                self.attack
+            } else {
+                self.release
+            };
+            self.envelope += coefficient * (current - self.envelope);
+
+            *sample = ((self.quiescent_voltage - self.plate_voltage) / swing) as f32 * post_gain;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.plate_voltage = self.quiescent_voltage;
+        self.envelope = self.quiescent_current;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.attack = 1.0 - (-1.0 / (TUBE_ATTACK_MS * 0.001 * sample_rate)).exp() as f64;
+        self.release = 1.0 - (-1.0 / (TUBE_RELEASE_MS * 0.001 * sample_rate)).exp() as f64;
+    }
+}
+
+/// Koren's plate current (A) for a grid and a plate voltage
+fn koren_current(grid_voltage: f64, plate_voltage: f64) -> f64 {
+    let e1 = plate_voltage / KOREN_KP
+        * (1.0
+            + (KOREN_KP
+                * (1.0 / KOREN_MU
+                    + grid_voltage / (KOREN_KVB + plate_voltage * plate_voltage).sqrt()))
+            .exp())
+        .ln();
+    if e1 > 0.0 {
+        2.0 * e1.powf(KOREN_EX) / KOREN_KG1
+    } else {
+        0.0
+    }
+}
+
+/// Grid current keeps the grid from going much above the cathode
+fn grid_conduction(grid_voltage: f64) -> f64 {
+    if grid_voltage > 0.0 {
+        grid_voltage / (1.0 + 2.0 * grid_voltage)
+    } else {
+        grid_voltage
+    }
+}
+
+//
+// Recurrent amp model, see `neural.rs`. Without a model the signal goes through unchanged.
+//
+pub struct NeuralAmp {
+    model: Option<NeuralModel>,
+}
+
+impl DistortionProcessor for NeuralAmp {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        match &mut self.model {
+            Some(model) => {
+                for sample in samples {
+                    *sample = model.process(*sample * pre_gain) * post_gain;
+                }
+            }
+            None => {
+                for sample in samples {
+                    *sample *= pre_gain * post_gain;
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(model) = &mut self.model {
+            model.reset();
+        }
+    }
+}
+
+//
+// Wavefolder: instead of being clipped, the signal exceeding +/-threshold is reflected back,
+// again and again for hotter inputs. The symmetry moves the positive and negative thresholds in
+// opposite directions. Small signals go through unchanged with the triangle shape.
+//
+pub struct Wavefolder {
+    positive_threshold: f32,
+    negative_threshold: f32,
+    shape: FoldShape,
+}
+
+/// Keeps the reflections count finite
+const MIN_FOLD_THRESHOLD: f32 = 0.05;
+
+impl Wavefolder {
+    pub fn new() -> Self {
+        Self {
+            positive_threshold: 0.5,
+            negative_threshold: 0.5,
+            shape: FoldShape::Triangle,
+        }
+    }
+
+    /// `symmetry` between -1 and 1, 0 folds both half-waves at the same threshold
+    pub fn set_fold(&mut self, threshold: f32, symmetry: f32, shape: FoldShape) {
+        self.positive_threshold = (threshold * (1.0 + symmetry)).max(MIN_FOLD_THRESHOLD);
+        self.negative_threshold = (threshold * (1.0 - symmetry)).max(MIN_FOLD_THRESHOLD);
+        self.shape = shape;
+    }
+}
+
+impl DistortionProcessor for Wavefolder {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        for sample in samples {
+            let x = *sample * pre_gain;
+            let threshold = if x >= 0.0 {
+                self.positive_threshold
+            } else {
+                self.negative_threshold
+            };
+            *sample = wavefolding(x, threshold, self.shape) * post_gain;
+        }
+    }
+}
+
+/// Both shapes peak at +/-threshold when x = +/-threshold
+pub fn wavefolding(x: f32, threshold: f32, shape: FoldShape) -> f32 {
+    let u = x / threshold;
+    let folded = match shape {
+        // Triangle wave of period 4 going through (0, 0) and (1, 1)
+        FoldShape::Triangle => 1.0 - ((u + 1.0).rem_euclid(4.0) - 2.0).abs(),
+        FoldShape::Sine => (u * FRAC_PI_2).sin(),
+    };
+    threshold * folded
+}
+
+//
+// Quantizes the (clipped) signal on 2^(bit_depth - 1) levels per polarity. The bit depth is
+// continuous so that it can be swept smoothly. The optional TPDF dither (two uniform noises of
+// one step each) decorrelates the quantization error from the signal.
+//
+pub struct Bitcrusher {
+    levels: f32,
+    dither: bool,
+    noise: WhiteNoise,
+}
+
+impl Bitcrusher {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            levels: 128.0,
+            dither: false,
+            noise: WhiteNoise::new(seed),
+        }
+    }
+
+    pub fn set_bit_depth(&mut self, bit_depth: f32, dither: bool) {
+        self.levels = (bit_depth.max(1.0) - 1.0).exp2();
+        self.dither = dither;
+    }
+}
+
+impl DistortionProcessor for Bitcrusher {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        for sample in samples {
+            let mut x = (*sample * pre_gain).clamp(-1.0, 1.0) * self.levels;
+            if self.dither {
+                x += self.noise.next() + self.noise.next();
+            }
+            *sample = x.round() / self.levels * post_gain;
+        }
+    }
+}
+
+//
+// Sample and hold decimation: the input is only sampled target_rate times per second and held in
+// between, without any anti-aliasing filter
+//
+pub struct SampleRateReducer {
+    sample_rate: f32,
+    target_rate: f32,
+    phase: f32,
+    held: f32,
+}
+
+impl SampleRateReducer {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            sample_rate,
+            target_rate: sample_rate,
+            phase: 1.0,
+            held: 0.0,
+        }
+    }
+
+    pub fn set_target_rate(&mut self, target_rate: f32) {
+        self.target_rate = target_rate;
+    }
+}
+
+impl DistortionProcessor for SampleRateReducer {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        let increment = (self.target_rate / self.sample_rate).min(1.0);
+        for sample in samples {
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+                self.held = *sample * pre_gain;
+            }
+            self.phase += increment;
+            *sample = self.held * post_gain;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.phase = 1.0;
+        self.held = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+    }
+}
+
+/// Uniform noise between -0.5 and 0.5 (xorshift32), cheap and allocation free
+struct WhiteNoise {
+    state: u32,
+}
+
+impl WhiteNoise {
+    /// Different seeds give uncorrelated sequences
+    fn new(seed: u32) -> Self {
+        // Spreads consecutive seeds over the whole sequence, xorshift must not start from 0
+        match seed.wrapping_add(1).wrapping_mul(0x9E37_79B9) {
+            0 => Self { state: 0x9E37_79B9 },
+            state => Self { state },
+        }
+    }
+
+    fn next(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32 - 0.5
+    }
+}
+
+/// A non linear function along with its first two antiderivatives
+pub trait Antiderivatives {
+    fn f(x: f64) -> f64;
+    fn f1(x: f64) -> f64;
+    fn f2(x: f64) -> f64;
+}
+
+pub struct SoftClipping;
+
+impl Antiderivatives for SoftClipping {
+    fn f(x: f64) -> f64 {
+        if x <= -1.0 {
+            -2.0 / 3.0
+        } else if x >= 1.0 {
+            2.0 / 3.0
+        } else {
+            x - x.powi(3) / 3.0
+        }
+    }
+
+    fn f1(x: f64) -> f64 {
+        if x.abs() >= 1.0 {
+            2.0 / 3.0 * x.abs() - 0.25
+        } else {
+            x.powi(2) / 2.0 - x.powi(4) / 12.0
+        }
+    }
+
+    fn f2(x: f64) -> f64 {
+        if x.abs() >= 1.0 {
+            let a = x.abs();
+            x.signum() * (0.15 + (a * a - 1.0) / 3.0 - (a - 1.0) / 4.0)
+        } else {
+            x.powi(3) / 6.0 - x.powi(5) / 60.0
+        }
+    }
+}
+
+pub struct HardClipping;
+
+impl Antiderivatives for HardClipping {
+    fn f(x: f64) -> f64 {
+        x.clamp(-1.0, 1.0)
+    }
+
+    fn f1(x: f64) -> f64 {
+        if x.abs() >= 1.0 {
+            x.abs() - 0.5
+        } else {
+            x * x / 2.0
+        }
+    }
+
+    fn f2(x: f64) -> f64 {
+        if x.abs() >= 1.0 {
+            let a = x.abs();
+            x.signum() * (a * a / 2.0 - a / 2.0 + 1.0 / 6.0)
+        } else {
+            x.powi(3) / 6.0
+        }
+    }
+}
+
+/// Below this input difference, the divided differences are ill-conditioned and replaced by their
+/// limit
+const ADAA_TOLERANCE: f64 = 1e-5;
+
+//
+// Antiderivative anti-aliasing: instead of sampling f, the output is the average of f over the
+// segment (1st order) or the triangle kernel (2nd order) joining the last input samples, computed
+// from the antiderivatives of f. See Parker, Zavalishin & Le Bivic, "Reducing the aliasing of
+// nonlinear waveshaping using continuous-time convolution" (DAFx 2016) and Bilbao, Esqueda,
+// Parker & Välimäki, "Antiderivative antialiasing for memoryless nonlinearities" (2017)
+//
+pub struct Adaa<C: Antiderivatives> {
+    order: AdaaOrder,
+    // Previous (pre gained) input samples
+    x1: f64,
+    x2: f64,
+    curve: PhantomData<C>,
+}
+
+impl<C: Antiderivatives> Adaa<C> {
+    pub fn new(order: AdaaOrder) -> Self {
+        Self {
+            order,
+            x1: 0.0,
+            x2: 0.0,
+            curve: PhantomData,
+        }
+    }
+
+    /// Clears the previous samples when the order changes, so this can be called for every block
+    pub fn set_order(&mut self, order: AdaaOrder) {
+        if order != self.order {
+            self.order = order;
+            self.reset();
+        }
+    }
+
+    fn first_order(&self, x: f64) -> f64 {
+        let delta = x - self.x1;
+        if delta.abs() < ADAA_TOLERANCE {
+            C::f((x + self.x1) / 2.0)
+        } else {
+            (C::f1(x) - C::f1(self.x1)) / delta
+        }
+    }
+
+    /// Divided difference of the second antiderivative
+    fn second_antiderivative_difference(x: f64, x1: f64) -> f64 {
+        let delta = x - x1;
+        if delta.abs() < ADAA_TOLERANCE {
+            C::f1((x + x1) / 2.0)
+        } else {
+            (C::f2(x) - C::f2(x1)) / delta
+        }
+    }
+
+    fn second_order(&self, x: f64) -> f64 {
+        let delta = x - self.x2;
+        if delta.abs() < ADAA_TOLERANCE {
+            let x_bar = (x + self.x2) / 2.0;
+            let delta = x_bar - self.x1;
+            if delta.abs() < ADAA_TOLERANCE {
+                C::f((x_bar + self.x1) / 2.0)
+            } else {
+                2.0 / delta * (C::f1(x_bar) + (C::f2(self.x1) - C::f2(x_bar)) / delta)
+            }
+        } else {
+            2.0 / delta
+                * (Self::second_antiderivative_difference(x, self.x1)
+                    - Self::second_antiderivative_difference(self.x1, self.x2))
+        }
+    }
+}
+
+impl<C: Antiderivatives> DistortionProcessor for Adaa<C> {
+    fn process(&mut self, pre_gain: f32, post_gain: f32, samples: &mut [f32]) {
+        for sample in samples {
+            let x = (*sample * pre_gain) as f64;
+            let y = match self.order {
+                AdaaOrder::Off => C::f(x),
+                AdaaOrder::First => self.first_order(x),
+                AdaaOrder::Second => self.second_order(x),
+            };
+            self.x2 = self.x1;
+            self.x1 = x;
+            *sample = y as f32 * post_gain;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.x1 = 0.0;
+        self.x2 = 0.0;
+    }
+}
+
+//
+// The kernel ADAA reduces to where the curve is linear: the average of the last two (1st order) or
+// three (2nd order) samples. It delays the types blended with the clippers by the same half or
+// whole sample, with the same slight high cut.
+//
+pub struct AdaaDelay {
+    x1: f32,
+    x2: f32,
+}
+
+impl AdaaDelay {
+    pub fn new() -> Self {
+        Self { x1: 0.0, x2: 0.0 }
+    }
+
+    pub fn process(&mut self, order: AdaaOrder, samples: &mut [f32]) {
+        for sample in samples {
+            let x = *sample;
+            *sample = match order {
+                AdaaOrder::Off => x,
+                AdaaOrder::First => (x + self.x1) / 2.0,
+                AdaaOrder::Second => (x + self.x1 + self.x2) / 3.0,
+            };
+            self.x2 = self.x1;
+            self.x1 = x;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.x1 = 0.0;
+        self.x2 = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A slow sine, which the anti-aliasing should only delay, going well past the clipping point
+    fn slow_sine(len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| 2.0 * (2.0 * std::f32::consts::PI * n as f32 / 2000.0).sin())
+            .collect()
+    }
+
+    fn antiderivatives_are_consistent<C: Antiderivatives>() {
+        // Off the kinks at +/-1, where the numerical derivatives average both sides
+        let h = 1e-4;
+        for i in -300..300 {
+            let x = (i as f64 + 0.5) / 100.0;
+            let f = (C::f1(x + h) - C::f1(x - h)) / (2.0 * h);
+            let f1 = (C::f2(x + h) - C::f2(x - h)) / (2.0 * h);
+            assert!((f - C::f(x)).abs() < 1e-6, "f at {}", x);
+            assert!((f1 - C::f1(x)).abs() < 1e-6, "f1 at {}", x);
+        }
+    }
+
+    #[test]
+    fn adaa_antiderivatives_are_consistent() {
+        antiderivatives_are_consistent::<SoftClipping>();
+        antiderivatives_are_consistent::<HardClipping>();
+    }
+
+    /// Largest difference between the anti-aliased curve and the static one, delayed by the order's
+    /// group delay
+    fn adaa_error<C: Antiderivatives>(order: AdaaOrder) -> f32 {
+        let input = slow_sine(4000);
+        let mut output = input.clone();
+        Adaa::<C>::new(order).process(1.0, 1.0, &mut output);
+        (2..input.len())
+            .map(|n| {
+                let expected = match order {
+                    AdaaOrder::Off => C::f(input[n] as f64),
+                    AdaaOrder::First => C::f((input[n] as f64 + input[n - 1] as f64) / 2.0),
+                    AdaaOrder::Second => C::f(input[n - 1] as f64),
+                };
+                (output[n] - expected as f32).abs()
+            })
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn adaa_matches_the_static_curve() {
+        for order in [AdaaOrder::Off, AdaaOrder::First, AdaaOrder::Second] {
+            let soft = adaa_error::<SoftClipping>(order);
+            let hard = adaa_error::<HardClipping>(order);
+            assert!(soft < 1e-3, "{:?} soft: {}", order, soft);
+            assert!(hard < 1e-3, "{:?} hard: {}", order, hard);
+        }
+    }
+
+    #[test]
+    fn adaa_of_a_constant_is_the_curve() {
+        for order in [AdaaOrder::First, AdaaOrder::Second] {
+            for x in [-3.0, -1.0, -0.5, 0.0, 0.25, 1.0, 2.0] {
+                let mut samples = [x; 8];
+                Adaa::<SoftClipping>::new(order).process(1.0, 1.0, &mut samples);
+                let expected = SoftClipping::f(x as f64) as f32;
+                assert!((samples[7] - expected).abs() < 1e-6, "{:?} at {}", order, x);
+            }
+        }
+    }
+
+    /// A quiet signal with plenty of high frequencies, where every curve is close to linear
+    fn quiet_signal(len: usize) -> Vec<f32> {
+        (0..len).map(|n| 1e-3 * (2.3 * n as f32).sin()).collect()
+    }
+
+    #[test]
+    fn adaa_delay_matches_adaa_where_the_curve_is_linear() {
+        for order in [AdaaOrder::Off, AdaaOrder::First, AdaaOrder::Second] {
+            let mut clipped = quiet_signal(256);
+            let mut delayed = clipped.clone();
+            Adaa::<HardClipping>::new(order).process(1.0, 1.0, &mut clipped);
+            AdaaDelay::new().process(order, &mut delayed);
+            for (clipped, delayed) in clipped.iter().zip(&delayed) {
+                assert!((clipped - delayed).abs() < 1e-8, "{:?}", order);
+            }
+        }
+    }
+
+    #[test]
+    fn morph_stays_in_phase_with_adaa() {
+        for order in [AdaaOrder::First, AdaaOrder::Second] {
+            let mut bank = DistortionBank::new(44100.0, 0);
+            bank.set_type(DistortionType::HARD);
+            bank.set_adaa_order(order);
+            bank.set_morph(DistortionType::TANH, 0.5);
+            let input = quiet_signal(512);
+            let mut samples = input.clone();
+            bank.process(1.0, 1.0, &mut samples[..256]);
+            bank.process(1.0, 1.0, &mut samples[256..]);
+            // Both halves are delayed alike, a comb filter would cancel the highest frequencies
+            let mut expected = input;
+            AdaaDelay::new().process(order, &mut expected);
+            for (sample, expected) in samples.iter().zip(&expected) {
+                assert!((sample - expected).abs() < 1e-8, "{:?}", order);
+            }
+        }
+    }
+
+    #[test]
+    fn restored_custom_curves_are_sanitized() {
+        let mut points = vec![
+            (0.5, 2.0),
+            (f32::NAN, 0.0),
+            (-0.2, -0.1),
+            (0.9, f32::INFINITY),
+        ];
+        sanitize_custom_curve(&mut points);
+        assert_eq!(points, [(-1.0, -0.1), (1.0, 1.0)]);
+
+        let mut points = vec![(0.3, 0.3)];
+        sanitize_custom_curve(&mut points);
+        assert_eq!(points, default_custom_curve());
+    }
+
+    #[test]
+    fn chebyshev_harmonics_have_the_exact_spectrum() {
+        let amounts = [0.5, 0.0, -0.25, 0.1, 0.0, 0.0, 0.05];
+        let mut generator = HarmonicGenerator::new();
+        generator.set_amounts(amounts);
+        // A full scale cosine over a whole number of periods
+        let period = 64;
+        let mut samples: Vec<f32> = (0..16 * period)
+            .map(|n| (2.0 * std::f32::consts::PI * n as f32 / period as f32).cos())
+            .collect();
+        generator.process(1.0, 1.0, &mut samples);
+
+        for harmonic in 0..=HARMONICS_COUNT + 2 {
+            let (mut cosine, mut sine) = (0.0, 0.0);
+            for (n, sample) in samples.iter().enumerate() {
+                let phase = 2.0 * std::f32::consts::PI * (harmonic * n) as f32 / period as f32;
+                cosine += sample * phase.cos();
+                sine += sample * phase.sin();
+            }
+            let scale = if harmonic == 0 { 1.0 } else { 2.0 } / samples.len() as f32;
+            let expected = match harmonic {
+                1 => 1.0,
+                2..=8 => amounts[harmonic - 2],
+                _ => 0.0,
+            };
+            assert!(
+                (cosine * scale - expected).abs() < 1e-4,
+                "harmonic {}",
+                harmonic
+            );
+            assert!((sine * scale).abs() < 1e-4, "harmonic {}", harmonic);
+        }
+    }
+
+    #[test]
+    fn diode_newton_raphson_converges() {
+        for diode_type in [DiodeType::Germanium, DiodeType::Silicon, DiodeType::Led] {
+            for sample_rate in [44100.0, 192000.0] {
+                for (capacitance, amplitude, frequency) in [
+                    (1.0, 100.0, 100.0),
+                    (10.0, 0.1, 5000.0),
+                    (10.0, 10.0, 5000.0),
+                    (100.0, 10.0, 100.0),
+                ] {
+                    let mut clipper = DiodeClipper::new(sample_rate);
+                    clipper.set_diode(diode_type, capacitance);
+                    let half_step = 0.5 / clipper.sample_rate;
+                    for n in 0..sample_rate as usize / 10 {
+                        let (v, vin) = (clipper.v, clipper.vin);
+                        let phase = 2.0 * std::f32::consts::PI * frequency * n as f32 / sample_rate;
+                        let mut sample = [amplitude * phase.sin()];
+                        clipper.process(1.0, 1.0, &mut sample);
+                        assert!(sample[0].is_finite());
+
+                        // Left of the trapezoidal rule's equation, relative to its slope: the
+                        // Newton step a new iteration would take
+                        let residual = clipper.v
+                            - v
+                            - half_step
+                                * (clipper.derivative(v, vin)
+                                    + clipper.derivative(clipper.v, clipper.vin));
+                        let slope = 1.0
+                            + half_step / clipper.capacitance
+                                * (1.0 / DIODE_RESISTANCE
+                                    + 2.0 * clipper.is / clipper.nvt
+                                        * (clipper.v / clipper.nvt).cosh());
+                        assert!(
+                            (residual / slope).abs() < 1e-8,
+                            "{:?} at {} Hz, {} nF, amplitude {}, {} Hz: {}",
+                            diode_type,
+                            sample_rate,
+                            capacitance,
+                            amplitude,
+                            frequency,
+                            residual / slope
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn three_bands_sum_to_the_crossovers_allpasses() {
+        let (sample_rate, low_frequency, high_frequency) = (48000.0, 200.0, 2000.0);
+        let mut multiband = MultibandDistortion::new(sample_rate, 0);
+        multiband.set_split(BandSplit::Three, low_frequency, high_frequency);
+        for band in 0..MAX_BANDS {
+            let dry = BandSettings {
+                dry_gain: 1.0,
+                wet_gain: 0.0,
+                ..BandSettings::default()
+            };
+            multiband.set_band(band, dry);
+        }
+        let allpass = |frequency| {
+            BiquadFilter::with_parameters(
+                frequency,
+                sample_rate,
+                BUTTERWORTH_Q,
+                0.0,
+                FilterType::ALLPASS,
+            )
+        };
+        let mut allpasses = [allpass(low_frequency), allpass(high_frequency)];
+
+        let mut state = 1u32;
+        let input: Vec<f32> = (0..4800)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 8) as f32 / (1 << 23) as f32 - 1.0
+            })
+            .collect();
+        let mut output = input.clone();
+        multiband.process(1.0, 1.0, &mut output);
+        for (mut sample, output) in input.into_iter().zip(output) {
+            for allpass in &mut allpasses {
+                sample = allpass.filter(&mut sample);
+            }
+            assert!((output - sample).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn bands_stay_in_phase_with_adaa() {
+        let (sample_rate, frequency) = (48000.0, 1000.0);
+        for order in [AdaaOrder::First, AdaaOrder::Second] {
+            let mut multiband = MultibandDistortion::new(sample_rate, 0);
+            multiband.set_split(BandSplit::Two, frequency, frequency);
+            multiband.set_adaa_order(order);
+            let types = [DistortionType::HARD, DistortionType::TANH];
+            for (band, distortion_type) in multiband.bands_mut().zip(types) {
+                band.set_stage(0, distortion_type, true, 1.0);
+                // The morph target counts even when unheard, it must not use ADAA either
+                band.set_morph(DistortionType::TANH, 0.0);
+            }
+            let mut allpass = BiquadFilter::with_parameters(
+                frequency,
+                sample_rate,
+                BUTTERWORTH_Q,
+                0.0,
+                FilterType::ALLPASS,
+            );
+
+            let input = quiet_signal(4800);
+            let mut output = input.clone();
+            multiband.process(1.0, 1.0, &mut output);
+            let mut expected: Vec<f32> = input
+                .into_iter()
+                .map(|mut sample| allpass.filter(&mut sample))
+                .collect();
+            AdaaDelay::new().process(order, &mut expected);
+            for (output, expected) in output.iter().zip(&expected) {
+                assert!((output - expected).abs() < 1e-7, "{:?}", order);
+            }
+        }
+    }
+
+    #[test]
+    fn muting_a_band_ramps_it_down() {
+        let mut multiband = MultibandDistortion::new(48000.0, 0);
+        multiband.set_split(BandSplit::Two, 200.0, 200.0);
+        let dry = BandSettings {
+            dry_gain: 1.0,
+            wet_gain: 0.0,
+            ..BandSettings::default()
+        };
+        multiband.set_band(0, dry);
+        multiband.set_band(1, dry);
+        // Only the low band passes DC
+        let mut samples = vec![1.0; 48000];
+        multiband.process(1.0, 1.0, &mut samples);
+
+        let muted = BandSettings {
+            audible: false,
+            ..dry
+        };
+        multiband.set_band(0, muted);
+        let mut samples = [1.0; 256];
+        multiband.process(1.0, 1.0, &mut samples);
+        for (index, sample) in samples.iter().enumerate() {
+            let expected = 1.0 - (index + 1) as f32 / samples.len() as f32;
+            assert!((sample - expected).abs() < 1e-3, "{}: {}", index, sample);
         }
-        *sample *= post_gain;
     }
 }
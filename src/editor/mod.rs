@@ -1,9 +1,9 @@
 use atomic_float::AtomicF32;
-use nih_plug::prelude::{util, Editor};
+use nih_plug::prelude::{util, AsyncExecutor, Editor};
 use nih_plug_vizia::vizia::image::{open, DynamicImage};
 use nih_plug_vizia::vizia::prelude::*;
 
-use nih_plug_vizia::widgets::{ParamSlider, PeakMeter, ResizeHandle};
+use nih_plug_vizia::widgets::{GenericUi, ParamButton, ParamSlider, PeakMeter, ResizeHandle};
 use nih_plug_vizia::{assets, create_vizia_editor, ViziaState, ViziaTheming};
 use std::fmt::Alignment;
 use std::sync::atomic::Ordering;
@@ -11,7 +11,8 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use crate::editor::widgets::categorical_picker::CategoricalPicker;
-use crate::DistAllParams;
+use crate::editor::widgets::curve_editor::CurveEditor;
+use crate::{DistAll, DistAllParams, DistAllTask};
 mod widgets;
 
 #[derive(Lens)]
@@ -19,17 +20,68 @@ struct Data {
     params: Arc<DistAllParams>,
     peak_meter_pre: Arc<AtomicF32>,
     peak_meter_post: Arc<AtomicF32>,
+    /// EXPRESSION formula as typed, even when it doesn't compile
+    expression: String,
+    /// Empty when `expression` compiles
+    expression_error: String,
+    async_executor: AsyncExecutor<DistAll>,
 }
 
-impl Model for Data {}
+enum ExpressionEvent {
+    Submit(String),
+}
+
+enum NeuralModelEvent {
+    Load(String),
+}
+
+impl Model for Data {
+    fn event(&mut self, _cx: &mut EventContext, event: &mut Event) {
+        event.map(|expression_event, _| match expression_event {
+            ExpressionEvent::Submit(source) => {
+                self.expression_error = match self.params.set_expression(source) {
+                    Ok(()) => String::new(),
+                    Err(error) => error.to_string(),
+                };
+                self.expression = source.clone();
+            }
+        });
+        event.map(|neural_model_event, _| match neural_model_event {
+            NeuralModelEvent::Load(path) => self
+                .async_executor
+                .execute_background(DistAllTask::LoadNeuralModel(path.clone())),
+        });
+    }
+}
+
+/// Rate the NEURAL stages run at, which differs from the model's one when the host's rate is too
+/// far from it or when a lo-fi type prevents resampling
+fn neural_rate_status(params: &DistAllParams) -> String {
+    let model_rate = params.neural_model_rate.load(Ordering::Relaxed);
+    let processing_rate = params.neural_processing_rate.load(Ordering::Relaxed);
+    if processing_rate == 0.0 {
+        String::new()
+    } else if processing_rate != model_rate {
+        format!(
+            "Running at {:.0} Hz instead of {:.0} Hz, the model will sound off",
+            processing_rate, model_rate
+        )
+    } else {
+        format!(
+            "Running at {:.0} Hz, the Oversampler is bypassed",
+            processing_rate
+        )
+    }
+}
 
 // Makes sense to also define this here, makes it a bit easier to keep track of
 pub(crate) fn default_state() -> Arc<ViziaState> {
-    ViziaState::new(|| (700, 250))
+    ViziaState::new(|| (700, 1120))
 }
 
 pub(crate) fn create(
     params: Arc<DistAllParams>,
+    async_executor: AsyncExecutor<DistAll>,
     peak_meter_pre: Arc<AtomicF32>,
     peak_meter_post: Arc<AtomicF32>,
     editor_state: Arc<ViziaState>,
@@ -42,6 +94,9 @@ pub(crate) fn create(
             params: params.clone(),
             peak_meter_pre: peak_meter_pre.clone(),
             peak_meter_post: peak_meter_post.clone(),
+            expression: params.expression.read().unwrap().clone(),
+            expression_error: String::new(),
+            async_executor: async_executor.clone(),
         }
         .build(cx);
 
@@ -88,6 +143,9 @@ pub(crate) fn create(
             VStack::new(cx, |cx: &mut Context| {
                 Label::new(cx, "Distortion");
                 CategoricalPicker::new(cx, Data::params, |params| &params.distortion);
+                CategoricalPicker::new(cx, Data::params, |params| &params.distortion_b);
+                ParamSlider::new(cx, Data::params, |params| &params.morph);
+                CategoricalPicker::new(cx, Data::params, |params| &params.adaa);
             })
             .class("control-panel")
             .child_left(Stretch(1.0))
@@ -101,16 +159,193 @@ pub(crate) fn create(
             .class("control-panel");
         })
         .class("row");
+        // Stages following the main distortion, in series
+        HStack::new(cx, |cx| {
+            VStack::new(cx, |cx: &mut Context| {
+                ParamButton::new(cx, Data::params, |params| &params.stage_2.enabled);
+                CategoricalPicker::new(cx, Data::params, |params| &params.stage_2.distortion);
+                ParamSlider::new(cx, Data::params, |params| &params.stage_2.drive);
+            })
+            .class("control-panel")
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0));
+            VStack::new(cx, |cx: &mut Context| {
+                ParamButton::new(cx, Data::params, |params| &params.stage_3.enabled);
+                CategoricalPicker::new(cx, Data::params, |params| &params.stage_3.distortion);
+                ParamSlider::new(cx, Data::params, |params| &params.stage_3.drive);
+            })
+            .class("control-panel")
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0));
+            VStack::new(cx, |cx: &mut Context| {
+                ParamButton::new(cx, Data::params, |params| &params.stage_4.enabled);
+                CategoricalPicker::new(cx, Data::params, |params| &params.stage_4.distortion);
+                ParamSlider::new(cx, Data::params, |params| &params.stage_4.drive);
+            })
+            .class("control-panel")
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0));
+        })
+        .class("row");
+        // Frequency bands distorted separately, their types replace the main distortion's
         HStack::new(cx, |cx| {
-            VStack::new(cx, |cx: &mut Context| {}); // TODO: fill or something
+            VStack::new(cx, |cx: &mut Context| {
+                Label::new(cx, "Bands");
+                CategoricalPicker::new(cx, Data::params, |params| &params.band_split);
+                ParamSlider::new(cx, Data::params, |params| &params.low_crossover);
+                ParamSlider::new(cx, Data::params, |params| &params.high_crossover);
+            })
+            .class("control-panel")
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0));
+            VStack::new(cx, |cx: &mut Context| {
+                Label::new(cx, "Band 1");
+                CategoricalPicker::new(cx, Data::params, |params| &params.band_1.distortion);
+                ParamSlider::new(cx, Data::params, |params| &params.band_1.drive);
+                ParamSlider::new(cx, Data::params, |params| &params.band_1.mix);
+                HStack::new(cx, |cx| {
+                    ParamButton::new(cx, Data::params, |params| &params.band_1.solo);
+                    ParamButton::new(cx, Data::params, |params| &params.band_1.mute);
+                });
+            })
+            .class("control-panel")
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0));
+            VStack::new(cx, |cx: &mut Context| {
+                Label::new(cx, "Band 2");
+                CategoricalPicker::new(cx, Data::params, |params| &params.band_2.distortion);
+                ParamSlider::new(cx, Data::params, |params| &params.band_2.drive);
+                ParamSlider::new(cx, Data::params, |params| &params.band_2.mix);
+                HStack::new(cx, |cx| {
+                    ParamButton::new(cx, Data::params, |params| &params.band_2.solo);
+                    ParamButton::new(cx, Data::params, |params| &params.band_2.mute);
+                });
+            })
+            .class("control-panel")
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0));
+            VStack::new(cx, |cx: &mut Context| {
+                Label::new(cx, "Band 3");
+                CategoricalPicker::new(cx, Data::params, |params| &params.band_3.distortion);
+                ParamSlider::new(cx, Data::params, |params| &params.band_3.drive);
+                ParamSlider::new(cx, Data::params, |params| &params.band_3.mix);
+                HStack::new(cx, |cx| {
+                    ParamButton::new(cx, Data::params, |params| &params.band_3.solo);
+                    ParamButton::new(cx, Data::params, |params| &params.band_3.mute);
+                });
+            })
+            .class("control-panel")
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0));
+        })
+        .class("row");
+        HStack::new(cx, |cx| {
+            VStack::new(cx, |cx| {
+                Label::new(cx, "Dry/Wet");
+                ParamSlider::new(cx, Data::params, |params| &params.mix).class("gain-slider");
+            })
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0))
+            .class("control-panel");
             VStack::new(cx, |cx: &mut Context| {
                 Label::new(cx, "Oversampling");
                 CategoricalPicker::new(cx, Data::params, |params| &params.oversampler);
+                CategoricalPicker::new(cx, Data::params, |params| &params.oversampling_factor);
+                ParamSlider::new(cx, Data::params, |params| &params.lanczos_radius);
             })
             .class("control-panel")
             .child_left(Stretch(1.0))
             .child_right(Stretch(1.0));
-            VStack::new(cx, |cx: &mut Context| {}); // TODO: fill or something
+            VStack::new(cx, |cx: &mut Context| {
+                Label::new(cx, "Mix Law");
+                CategoricalPicker::new(cx, Data::params, |params| &params.mix_law);
+            })
+            .class("control-panel")
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0));
+        })
+        .class("row");
+        HStack::new(cx, |cx| {
+            VStack::new(cx, |cx| {
+                Label::new(cx, "Emphasis");
+                ParamSlider::new(cx, Data::params, |params| &params.emphasis_tilt);
+                ParamSlider::new(cx, Data::params, |params| &params.emphasis_frequency);
+                ParamSlider::new(cx, Data::params, |params| &params.emphasis_amount);
+            })
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0))
+            .class("control-panel");
+            VStack::new(cx, |cx: &mut Context| {
+                Label::new(cx, "Tone");
+                CategoricalPicker::new(cx, Data::params, |params| &params.tone_mode);
+                ParamSlider::new(cx, Data::params, |params| &params.tone_cutoff);
+                ParamSlider::new(cx, Data::params, |params| &params.tone_resonance);
+                ParamSlider::new(cx, Data::params, |params| &params.tone_envelope);
+            })
+            .class("control-panel")
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0));
+        })
+        .class("row");
+        HStack::new(cx, |cx| {
+            VStack::new(cx, |cx| {
+                Label::new(cx, "Curve");
+                // Parameters specific to some distortion types, only the ones matching the selected
+                // type have an effect
+                ScrollView::new(cx, 0.0, 0.0, false, true, |cx| {
+                    GenericUi::new(cx, Data::params.map(|params| params.curve.clone()))
+                        .width(Percentage(100.0))
+                        .height(Auto);
+                })
+                .width(Percentage(100.0));
+            })
+            .class("control-panel");
+            VStack::new(cx, |cx| {
+                Label::new(cx, "Custom Curve");
+                CurveEditor::new(
+                    cx,
+                    params.custom_curve.clone(),
+                    params.custom_curve_changed.clone(),
+                )
+                .width(Pixels(150.0))
+                .height(Pixels(150.0));
+            })
+            .class("control-panel")
+            .width(Auto)
+            .child_left(Stretch(1.0))
+            .child_right(Stretch(1.0));
+        })
+        .class("row");
+        HStack::new(cx, |cx| {
+            VStack::new(cx, |cx| {
+                Label::new(cx, "Expression");
+                Textbox::new(cx, Data::expression)
+                    .on_submit(|cx, source, _| cx.emit(ExpressionEvent::Submit(source)))
+                    .class("category")
+                    .width(Stretch(1.0));
+                Label::new(cx, Data::expression_error);
+            })
+            .class("control-panel");
+        })
+        .class("row");
+        HStack::new(cx, |cx| {
+            VStack::new(cx, |cx| {
+                Label::new(cx, "Neural Model");
+                // Path of the JSON weights file, loaded in the background when submitted
+                Textbox::new(
+                    cx,
+                    Data::params.map(|params| params.neural_model_path.read().unwrap().clone()),
+                )
+                .on_submit(|cx, path, _| cx.emit(NeuralModelEvent::Load(path)))
+                .class("category")
+                .width(Stretch(1.0));
+                Label::new(
+                    cx,
+                    Data::params.map(|params| params.neural_model_status.read().unwrap().clone()),
+                );
+                Label::new(cx, Data::params.map(|params| neural_rate_status(params)));
+            })
+            .class("control-panel");
         })
         .class("row");
 
@@ -0,0 +1,184 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use nih_plug_vizia::vizia::prelude::*;
+use nih_plug_vizia::vizia::vg;
+
+use crate::distortions::spline;
+
+/// Distance in pixels under which a click grabs a control point
+const GRAB_RADIUS: f32 = 8.0;
+const POINT_RADIUS: f32 = 4.0;
+/// Number of segments the curve is drawn with
+const CURVE_RESOLUTION: usize = 128;
+
+/// Transfer curve editor on the input -> output plane, both spanning [-1, 1]. A left click grabs
+/// the closest control point or adds a new one, which can then be dragged between its neighbours.
+/// A right click removes a point. The end points can only move vertically.
+pub struct CurveEditor {
+    points: Arc<RwLock<Vec<(f32, f32)>>>,
+    changed: Arc<AtomicBool>,
+    dragged: Option<usize>,
+    /// Last known cursor position, in pixels
+    cursor: (f32, f32),
+}
+
+impl CurveEditor {
+    pub fn new(
+        cx: &mut Context,
+        points: Arc<RwLock<Vec<(f32, f32)>>>,
+        changed: Arc<AtomicBool>,
+    ) -> Handle<Self> {
+        Self {
+            points,
+            changed,
+            dragged: None,
+            cursor: (0.0, 0.0),
+        }
+        .build(cx, |_| {})
+    }
+
+    fn grabbed(bounds: BoundingBox, points: &[(f32, f32)], cursor: (f32, f32)) -> Option<usize> {
+        points
+            .iter()
+            .map(|&point| {
+                let (x, y) = to_screen(bounds, point);
+                (x - cursor.0).hypot(y - cursor.1)
+            })
+            .enumerate()
+            .filter(|(_, distance)| *distance <= GRAB_RADIUS)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, _)| index)
+    }
+
+    /// Move a point, keeping the points sorted by input
+    fn drag(points: &mut [(f32, f32)], index: usize, (x, y): (f32, f32)) {
+        let last = points.len() - 1;
+        points[index] = if index == 0 || index == last {
+            (points[index].0, y)
+        } else {
+            (x.clamp(points[index - 1].0, points[index + 1].0), y)
+        };
+    }
+}
+
+impl View for CurveEditor {
+    fn element(&self) -> Option<&'static str> {
+        Some("curve-editor")
+    }
+
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|window_event, meta| match window_event {
+            WindowEvent::MouseDown(MouseButton::Left) => {
+                let bounds = cx.bounds();
+                let mut points = self.points.write().unwrap();
+                self.dragged = match Self::grabbed(bounds, &points, self.cursor) {
+                    Some(index) => Some(index),
+                    None => {
+                        let point = to_plane(bounds, self.cursor);
+                        match points.iter().position(|other| other.0 > point.0) {
+                            Some(index) if index > 0 => {
+                                points.insert(index, point);
+                                Some(index)
+                            }
+                            _ => None,
+                        }
+                    }
+                };
+                if self.dragged.is_some() {
+                    self.changed.store(true, Ordering::Relaxed);
+                    cx.capture();
+                    cx.needs_redraw();
+                }
+                meta.consume();
+            }
+            WindowEvent::MouseDown(MouseButton::Right) => {
+                let mut points = self.points.write().unwrap();
+                if let Some(index) = Self::grabbed(cx.bounds(), &points, self.cursor) {
+                    if index != 0 && index != points.len() - 1 {
+                        points.remove(index);
+                        self.changed.store(true, Ordering::Relaxed);
+                        cx.needs_redraw();
+                    }
+                }
+                meta.consume();
+            }
+            WindowEvent::MouseMove(x, y) => {
+                self.cursor = (*x, *y);
+                if let Some(index) = self.dragged {
+                    let point = to_plane(cx.bounds(), self.cursor);
+                    Self::drag(&mut self.points.write().unwrap(), index, point);
+                    self.changed.store(true, Ordering::Relaxed);
+                    cx.needs_redraw();
+                    meta.consume();
+                }
+            }
+            WindowEvent::MouseUp(MouseButton::Left) => {
+                if self.dragged.take().is_some() {
+                    cx.release();
+                    meta.consume();
+                }
+            }
+            _ => {}
+        });
+    }
+
+    fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
+        let bounds = cx.bounds();
+        let points = match self.points.read() {
+            Ok(points) => points,
+            Err(_) => return,
+        };
+
+        let mut background = vg::Path::new();
+        background.rect(bounds.x, bounds.y, bounds.w, bounds.h);
+        canvas.fill_path(&mut background, &vg::Paint::color(vg::Color::rgb(18, 8, 8)));
+
+        // Identity line, for reference
+        let mut identity = vg::Path::new();
+        let (x0, y0) = to_screen(bounds, (-1.0, -1.0));
+        let (x1, y1) = to_screen(bounds, (1.0, 1.0));
+        identity.move_to(x0, y0);
+        identity.line_to(x1, y1);
+        canvas.stroke_path(
+            &mut identity,
+            &vg::Paint::color(vg::Color::rgb(80, 80, 80)).with_line_width(1.0),
+        );
+
+        let mut curve = vg::Path::new();
+        for step in 0..=CURVE_RESOLUTION {
+            let x = step as f32 / CURVE_RESOLUTION as f32 * 2.0 - 1.0;
+            let (x, y) = to_screen(bounds, (x, spline(&points, x).clamp(-1.0, 1.0)));
+            if step == 0 {
+                curve.move_to(x, y);
+            } else {
+                curve.line_to(x, y);
+            }
+        }
+        canvas.stroke_path(
+            &mut curve,
+            &vg::Paint::color(vg::Color::rgb(184, 191, 254)).with_line_width(2.0),
+        );
+
+        for &point in points.iter() {
+            let (x, y) = to_screen(bounds, point);
+            let mut handle = vg::Path::new();
+            handle.circle(x, y, POINT_RADIUS);
+            canvas.fill_path(&mut handle, &vg::Paint::color(vg::Color::rgb(165, 25, 25)));
+        }
+    }
+}
+
+fn to_screen(bounds: BoundingBox, (x, y): (f32, f32)) -> (f32, f32) {
+    (
+        bounds.x + (x + 1.0) * 0.5 * bounds.w,
+        bounds.y + (1.0 - y) * 0.5 * bounds.h,
+    )
+}
+
+fn to_plane(bounds: BoundingBox, (x, y): (f32, f32)) -> (f32, f32) {
+    (
+        ((x - bounds.x) / bounds.w * 2.0 - 1.0).clamp(-1.0, 1.0),
+        (1.0 - (y - bounds.y) / bounds.h * 2.0).clamp(-1.0, 1.0),
+    )
+}
@@ -1 +1,2 @@
 pub mod categorical_picker;
+pub mod curve_editor;
@@ -0,0 +1,355 @@
+use std::fmt;
+
+/// The longest program an expression can compile to
+pub const MAX_PROGRAM_SIZE: usize = 64;
+/// The deepest evaluation stack a program may need
+pub const MAX_STACK_DEPTH: usize = 16;
+/// The deepest nesting of parentheses, function calls, negations and powers the parser accepts
+const MAX_NESTING: usize = 32;
+
+pub const DEFAULT_EXPRESSION: &str = "tanh(2*x)";
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Input,
+    Constant(f32),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Neg,
+    Min,
+    Max,
+    Function(fn(f32) -> f32),
+}
+
+//
+// Transfer function typed by the user, as a function of the input x. The source is compiled into
+// a fixed size program run on a stack machine: compiling may happen on any thread, copying and
+// evaluating a program never allocates, so that it can be used on the audio thread.
+//
+// Grammar, with the usual precedences and a right associative ^:
+//   expression := term (('+' | '-') term)*
+//   term := unary (('*' | '/') unary)*
+//   unary := '-' unary | power
+//   power := primary ('^' unary)?
+//   primary := number | 'x' | 'pi' | 'e' | function '(' expression (',' expression)? ')'
+//            | '(' expression ')'
+//
+#[derive(Debug, Clone, Copy)]
+pub struct Program {
+    ops: [Op; MAX_PROGRAM_SIZE],
+    len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Byte offset in the source
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at character {}", self.message, self.position + 1)
+    }
+}
+
+impl Default for Program {
+    /// The identity, x
+    fn default() -> Self {
+        Self {
+            ops: [Op::Input; MAX_PROGRAM_SIZE],
+            len: 1,
+        }
+    }
+}
+
+impl Program {
+    pub fn compile(source: &str) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            source: source.as_bytes(),
+            position: 0,
+            program: Self {
+                ops: [Op::Input; MAX_PROGRAM_SIZE],
+                len: 0,
+            },
+            depth: 0,
+            nesting: 0,
+        };
+        parser.expression()?;
+        parser.skip_whitespace();
+        if parser.position < parser.source.len() {
+            return Err(parser.error("Unexpected character"));
+        }
+        Ok(parser.program)
+    }
+
+    pub fn eval(&self, x: f32) -> f32 {
+        let mut stack = [0.0; MAX_STACK_DEPTH];
+        let mut top = 0;
+        for op in &self.ops[..self.len] {
+            match *op {
+                Op::Input => {
+                    stack[top] = x;
+                    top += 1;
+                }
+                Op::Constant(value) => {
+                    stack[top] = value;
+                    top += 1;
+                }
+                Op::Neg => stack[top - 1] = -stack[top - 1],
+                Op::Function(function) => stack[top - 1] = function(stack[top - 1]),
+                binary => {
+                    top -= 1;
+                    let (a, b) = (stack[top - 1], stack[top]);
+                    stack[top - 1] = match binary {
+                        Op::Add => a + b,
+                        Op::Sub => a - b,
+                        Op::Mul => a * b,
+                        Op::Div => a / b,
+                        Op::Pow => a.powf(b),
+                        Op::Min => a.min(b),
+                        Op::Max => a.max(b),
+                        _ => unreachable!(),
+                    };
+                }
+            }
+        }
+        stack[0]
+    }
+}
+
+struct Parser<'a> {
+    source: &'a [u8],
+    position: usize,
+    program: Program,
+    /// Stack depth reached by the program so far
+    depth: usize,
+    /// Current recursion depth of the parser
+    nesting: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &'static str) -> ParseError {
+        ParseError {
+            position: self.position,
+            message,
+        }
+    }
+
+    fn emit(&mut self, op: Op) -> Result<(), ParseError> {
+        if self.program.len == MAX_PROGRAM_SIZE {
+            return Err(self.error("Expression too long"));
+        }
+        match op {
+            Op::Input | Op::Constant(_) => self.depth += 1,
+            Op::Neg | Op::Function(_) => {}
+            _ => self.depth -= 1,
+        }
+        if self.depth > MAX_STACK_DEPTH {
+            return Err(self.error("Expression too deeply nested"));
+        }
+        self.program.ops[self.program.len] = op;
+        self.program.len += 1;
+        Ok(())
+    }
+
+    /// Run a nested rule, failing instead of overflowing the stack on pathological input
+    fn nested(
+        &mut self,
+        rule: impl FnOnce(&mut Self) -> Result<(), ParseError>,
+    ) -> Result<(), ParseError> {
+        if self.nesting == MAX_NESTING {
+            return Err(self.error("Expression too deeply nested"));
+        }
+        self.nesting += 1;
+        let result = rule(self);
+        self.nesting -= 1;
+        result
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .source
+            .get(self.position)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.position += 1;
+        }
+    }
+
+    /// Consume the next non blank character if it is the expected one
+    fn accept(&mut self, expected: u8) -> bool {
+        self.skip_whitespace();
+        if self.source.get(self.position) == Some(&expected) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: u8, message: &'static str) -> Result<(), ParseError> {
+        if self.accept(expected) {
+            Ok(())
+        } else {
+            Err(self.error(message))
+        }
+    }
+
+    fn expression(&mut self) -> Result<(), ParseError> {
+        self.term()?;
+        loop {
+            if self.accept(b'+') {
+                self.term()?;
+                self.emit(Op::Add)?;
+            } else if self.accept(b'-') {
+                self.term()?;
+                self.emit(Op::Sub)?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<(), ParseError> {
+        self.unary()?;
+        loop {
+            if self.accept(b'*') {
+                self.unary()?;
+                self.emit(Op::Mul)?;
+            } else if self.accept(b'/') {
+                self.unary()?;
+                self.emit(Op::Div)?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<(), ParseError> {
+        if self.accept(b'-') {
+            self.nested(Self::unary)?;
+            self.emit(Op::Neg)
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<(), ParseError> {
+        self.primary()?;
+        if self.accept(b'^') {
+            self.nested(Self::unary)?;
+            self.emit(Op::Pow)?;
+        }
+        Ok(())
+    }
+
+    fn primary(&mut self) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        let start = self.position;
+        match self.source.get(start) {
+            Some(b'(') => {
+                self.position += 1;
+                self.nested(Self::expression)?;
+                self.expect(b')', "Missing closing parenthesis")
+            }
+            Some(c) if c.is_ascii_digit() || *c == b'.' => {
+                while self
+                    .source
+                    .get(self.position)
+                    .is_some_and(|c| c.is_ascii_digit() || *c == b'.')
+                {
+                    self.position += 1;
+                }
+                // Only ASCII digits and dots were consumed
+                let number = std::str::from_utf8(&self.source[start..self.position]).unwrap();
+                match number.parse() {
+                    Ok(value) => self.emit(Op::Constant(value)),
+                    Err(_) => Err(ParseError {
+                        position: start,
+                        message: "Invalid number",
+                    }),
+                }
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                while self
+                    .source
+                    .get(self.position)
+                    .is_some_and(u8::is_ascii_alphanumeric)
+                {
+                    self.position += 1;
+                }
+                self.identifier(start)
+            }
+            Some(_) => Err(self.error("Unexpected character")),
+            None => Err(self.error("Unexpected end of expression")),
+        }
+    }
+
+    fn identifier(&mut self, start: usize) -> Result<(), ParseError> {
+        let unary: fn(f32) -> f32 = match &self.source[start..self.position] {
+            b"x" => return self.emit(Op::Input),
+            b"pi" => return self.emit(Op::Constant(std::f32::consts::PI)),
+            b"e" => return self.emit(Op::Constant(std::f32::consts::E)),
+            b"min" => return self.binary_function(Op::Min),
+            b"max" => return self.binary_function(Op::Max),
+            b"sin" => f32::sin,
+            b"cos" => f32::cos,
+            b"tan" => f32::tan,
+            b"tanh" => f32::tanh,
+            b"atan" => f32::atan,
+            b"abs" => f32::abs,
+            b"sqrt" => f32::sqrt,
+            b"exp" => f32::exp,
+            b"ln" => f32::ln,
+            b"sign" => f32::signum,
+            _ => {
+                return Err(ParseError {
+                    position: start,
+                    message: "Unknown name",
+                })
+            }
+        };
+        self.expect(b'(', "Missing opening parenthesis")?;
+        self.nested(Self::expression)?;
+        self.expect(b')', "Missing closing parenthesis")?;
+        self.emit(Op::Function(unary))
+    }
+
+    fn binary_function(&mut self, op: Op) -> Result<(), ParseError> {
+        self.expect(b'(', "Missing opening parenthesis")?;
+        self.nested(Self::expression)?;
+        self.expect(b',', "Missing second argument")?;
+        self.nested(Self::expression)?;
+        self.expect(b')', "Missing closing parenthesis")?;
+        self.emit(op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        for source in [
+            "-".repeat(100_000) + "x",
+            "(".repeat(100_000) + "x",
+            "sin(".repeat(100_000) + "x",
+            "x^".repeat(100_000) + "x",
+        ] {
+            let error = Program::compile(&source).unwrap_err();
+            assert_eq!(error.message, "Expression too deeply nested");
+        }
+    }
+
+    #[test]
+    fn reasonable_nesting_compiles() {
+        let source = format!("{}x{}", "(".repeat(20), ")".repeat(20));
+        assert_eq!(Program::compile(&source).unwrap().eval(0.5), 0.5);
+        assert_eq!(Program::compile("-(-(-x))").unwrap().eval(0.5), -0.5);
+    }
+}
@@ -1,7 +1,20 @@
 use std::f32::consts::PI;
 
+//
+// Biquads follow the RBJ audio EQ cookbook. Q sets the resonance of the passes and the width of
+// the others, the gain in dB only applies to PEAK and the shelves
+//
+#[allow(dead_code)] // The full family, whether the plugin uses every type or not
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FilterType {
     LOWPASS,
+    HIGHPASS,
+    BANDPASS,
+    NOTCH,
+    ALLPASS,
+    PEAK,
+    LOWSHELF,
+    HIGHSHELF,
 }
 
 pub trait Filter {
@@ -25,35 +38,133 @@ pub struct BiquadFilter {
     s2: f32,
 }
 
-impl Filter for BiquadFilter {
-    fn new(cutoff_frequency: i32, sample_rate: i32, filter_type: FilterType) -> Self {
-        let q = 3.0;
-        let omega = (2.0 * PI * cutoff_frequency as f32) / sample_rate as f32;
-
-        let alpha = omega.sin() / (2.0 * q);
-
-        let a0 = 1.0 + alpha;
-
-        let coefficients = match filter_type {
-            FilterType::LOWPASS => {
-                let coefficients = BiquadCoefficients {
-                    a1: (-2.0 * omega.cos()) / a0,
-                    a2: (1.0 - alpha) / a0,
-                    b0: ((1.0 - omega.cos()) / 2.0) / a0,
-                    b1: (1.0 - omega.cos()) / a0,
-                    b2: ((1.0 - omega.cos()) / 2.0) / a0,
-                };
-                coefficients
-            }
+impl BiquadCoefficients {
+    fn new(
+        cutoff_frequency: f32,
+        sample_rate: f32,
+        q: f32,
+        gain_db: f32,
+        filter_type: FilterType,
+    ) -> Self {
+        let omega = (2.0 * PI * cutoff_frequency) / sample_rate;
+        let (sin, cos) = omega.sin_cos();
+        let alpha = sin / (2.0 * q);
+        // Square root of the linear gain
+        let a = 10.0f32.powf(gain_db / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match filter_type {
+            FilterType::LOWPASS => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterType::HIGHPASS => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            // Unity gain at the center frequency
+            FilterType::BANDPASS => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::NOTCH => (1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::ALLPASS => (
+                1.0 - alpha,
+                -2.0 * cos,
+                1.0 + alpha,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterType::PEAK => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            FilterType::LOWSHELF => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            FilterType::HIGHSHELF => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
         };
 
+        BiquadCoefficients {
+            a1: a1 / a0,
+            a2: a2 / a0,
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+        }
+    }
+}
+
+impl BiquadFilter {
+    /// `Filter::new` uses a fixed Q of 3 and no gain
+    pub fn with_parameters(
+        cutoff_frequency: f32,
+        sample_rate: f32,
+        q: f32,
+        gain_db: f32,
+        filter_type: FilterType,
+    ) -> Self {
         Self {
-            coefficients: coefficients,
+            coefficients: BiquadCoefficients::new(
+                cutoff_frequency,
+                sample_rate,
+                q,
+                gain_db,
+                filter_type,
+            ),
             s1: 0.0,
             s2: 0.0,
         }
     }
 
+    /// Recompute the coefficients, the state is kept so that it can be called every block while
+    /// filtering
+    pub fn update_coefficients(
+        &mut self,
+        cutoff_frequency: f32,
+        sample_rate: f32,
+        q: f32,
+        gain_db: f32,
+        filter_type: FilterType,
+    ) {
+        self.coefficients =
+            BiquadCoefficients::new(cutoff_frequency, sample_rate, q, gain_db, filter_type);
+    }
+}
+
+impl Filter for BiquadFilter {
+    fn new(cutoff_frequency: i32, sample_rate: i32, filter_type: FilterType) -> Self {
+        Self::with_parameters(
+            cutoff_frequency as f32,
+            sample_rate as f32,
+            3.0,
+            0.0,
+            filter_type,
+        )
+    }
+
     fn filter(&mut self, sample: &mut f32) -> f32 {
         let result = self.coefficients.b0 * *sample + self.s1;
 
@@ -67,3 +178,478 @@ impl Filter for BiquadFilter {
         self.s2 = 0.0;
     }
 }
+
+//
+// First order filter: y[n] = b0 * x[n] + b1 * x[n - 1] + a1 * y[n - 1]
+// The highpass is normalized to unity gain at Nyquist, with a cutoff of a few Hz it makes a DC
+// blocker
+//
+pub struct OnePoleFilter {
+    a1: f32,
+    b0: f32,
+    b1: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl Filter for OnePoleFilter {
+    fn new(cutoff_frequency: i32, sample_rate: i32, filter_type: FilterType) -> Self {
+        let pole = (-2.0 * PI * cutoff_frequency as f32 / sample_rate as f32).exp();
+        let (b0, b1) = match filter_type {
+            FilterType::LOWPASS => (1.0 - pole, 0.0),
+            FilterType::HIGHPASS => ((1.0 + pole) / 2.0, -(1.0 + pole) / 2.0),
+            // Only lowpass and highpass are possible with one pole, the others fall back to lowpass
+            _ => {
+                debug_assert!(false, "One pole filters are lowpass or highpass only");
+                (1.0 - pole, 0.0)
+            }
+        };
+
+        Self {
+            a1: pole,
+            b0,
+            b1,
+            x1: 0.0,
+            y1: 0.0,
+        }
+    }
+
+    fn filter(&mut self, sample: &mut f32) -> f32 {
+        let result = self.b0 * *sample + self.b1 * self.x1 + self.a1 * self.y1;
+        self.x1 = *sample;
+        self.y1 = result;
+        result
+    }
+
+    fn reset(&mut self) {
+        self.x1 = 0.0;
+        self.y1 = 0.0;
+    }
+}
+
+/// The filter types a state variable filter can output, it has no gain
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SvfOutput {
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+    Allpass,
+}
+
+impl SvfOutput {
+    /// The types with a gain fall back to lowpass
+    fn new(filter_type: FilterType) -> Self {
+        match filter_type {
+            FilterType::LOWPASS => Self::Lowpass,
+            FilterType::HIGHPASS => Self::Highpass,
+            FilterType::BANDPASS => Self::Bandpass,
+            FilterType::NOTCH => Self::Notch,
+            FilterType::ALLPASS => Self::Allpass,
+            FilterType::PEAK | FilterType::LOWSHELF | FilterType::HIGHSHELF => {
+                debug_assert!(false, "State variable filters have no gain");
+                Self::Lowpass
+            }
+        }
+    }
+}
+
+/// Simultaneous outputs of the state variable filter
+#[derive(Debug, Clone, Copy)]
+pub struct SvfOutputs {
+    pub lowpass: f32,
+    pub bandpass: f32,
+    pub highpass: f32,
+}
+
+//
+// Zero delay feedback state variable filter, discretized with the topology preserving transform
+// (trapezoidal integrators, as described by Andrew Simper). Unlike the direct form biquad, its
+// state stays meaningful when the coefficients change, so the cutoff and Q may be modulated every
+// sample. `Filter::filter` returns the output selected by the filter type, LOWPASS, HIGHPASS,
+// BANDPASS, NOTCH or ALLPASS, `process` all of them at once
+//
+pub struct StateVariableFilter {
+    output: SvfOutput,
+    sample_rate: f32,
+    /// Damping, the inverse of Q
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl StateVariableFilter {
+    pub fn with_q(
+        cutoff_frequency: f32,
+        sample_rate: f32,
+        q: f32,
+        filter_type: FilterType,
+    ) -> Self {
+        let mut filter = Self {
+            output: SvfOutput::new(filter_type),
+            sample_rate,
+            k: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+        };
+        filter.set(cutoff_frequency, q);
+        filter
+    }
+
+    /// Cheap enough to be called every sample. The cutoff is kept below Nyquist
+    pub fn set(&mut self, cutoff_frequency: f32, q: f32) {
+        let cutoff_frequency = cutoff_frequency.clamp(1.0, self.sample_rate * 0.49);
+        let g = (PI * cutoff_frequency / self.sample_rate).tan();
+        self.k = 1.0 / q;
+        self.a1 = 1.0 / (1.0 + g * (g + self.k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
+    }
+
+    pub fn process(&mut self, sample: f32) -> SvfOutputs {
+        let v3 = sample - self.ic2eq;
+        let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+        let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+        SvfOutputs {
+            lowpass: v2,
+            bandpass: v1,
+            highpass: sample - self.k * v1 - v2,
+        }
+    }
+}
+
+impl Filter for StateVariableFilter {
+    fn new(cutoff_frequency: i32, sample_rate: i32, filter_type: FilterType) -> Self {
+        Self::with_q(
+            cutoff_frequency as f32,
+            sample_rate as f32,
+            BUTTERWORTH_Q,
+            filter_type,
+        )
+    }
+
+    fn filter(&mut self, sample: &mut f32) -> f32 {
+        let outputs = self.process(*sample);
+        match self.output {
+            SvfOutput::Lowpass => outputs.lowpass,
+            SvfOutput::Highpass => outputs.highpass,
+            SvfOutput::Bandpass => outputs.bandpass,
+            SvfOutput::Notch => outputs.lowpass + outputs.highpass,
+            SvfOutput::Allpass => outputs.lowpass - self.k * outputs.bandpass + outputs.highpass,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ic1eq = 0.0;
+        self.ic2eq = 0.0;
+    }
+}
+
+/// Q of the Butterworth biquads an LR4 crossover is made of
+pub const BUTTERWORTH_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+//
+// Linkwitz-Riley crossover of order 4: each output goes through two cascaded Butterworth biquads,
+// so that both are 6 dB down at the crossover frequency and in phase. Their sum is the ALLPASS
+// biquad at the same frequency with a Butterworth Q, which other bands must go through to stay
+// aligned
+//
+pub struct Crossover {
+    lowpass: [BiquadFilter; 2],
+    highpass: [BiquadFilter; 2],
+}
+
+impl Crossover {
+    pub fn new(frequency: f32, sample_rate: f32) -> Self {
+        let lowpass = || {
+            BiquadFilter::with_parameters(
+                frequency,
+                sample_rate,
+                BUTTERWORTH_Q,
+                0.0,
+                FilterType::LOWPASS,
+            )
+        };
+        let highpass = || {
+            BiquadFilter::with_parameters(
+                frequency,
+                sample_rate,
+                BUTTERWORTH_Q,
+                0.0,
+                FilterType::HIGHPASS,
+            )
+        };
+        Self {
+            lowpass: [lowpass(), lowpass()],
+            highpass: [highpass(), highpass()],
+        }
+    }
+
+    /// Keeps the state, it may be called while filtering
+    pub fn set_frequency(&mut self, frequency: f32, sample_rate: f32) {
+        for filter in &mut self.lowpass {
+            filter.update_coefficients(
+                frequency,
+                sample_rate,
+                BUTTERWORTH_Q,
+                0.0,
+                FilterType::LOWPASS,
+            );
+        }
+        for filter in &mut self.highpass {
+            filter.update_coefficients(
+                frequency,
+                sample_rate,
+                BUTTERWORTH_Q,
+                0.0,
+                FilterType::HIGHPASS,
+            );
+        }
+    }
+
+    /// Returns the (low, high) bands
+    pub fn split(&mut self, sample: f32) -> (f32, f32) {
+        let (mut low, mut high) = (sample, sample);
+        for filter in &mut self.lowpass {
+            low = filter.filter(&mut low);
+        }
+        for filter in &mut self.highpass {
+            high = filter.filter(&mut high);
+        }
+        (low, high)
+    }
+
+    pub fn reset(&mut self) {
+        for filter in self.lowpass.iter_mut().chain(self.highpass.iter_mut()) {
+            filter.reset();
+        }
+    }
+}
+
+/// Width of the emphasis peak
+const EMPHASIS_Q: f32 = 0.7;
+
+//
+// Pre-emphasis EQ before the distortion and its inverse after it: a peak of `amount` dB at
+// `frequency`, and a tilt of `tilt` dB between shelves around the same frequency, positive tilts
+// favouring the highs. The de-emphasis uses the same biquads with opposite gains, which are their
+// exact inverses: where the distortion is linear the tonal balance is unchanged. Both cascades
+// get their new coefficients in the same block, while the de-emphasis sees the audio delayed by
+// the oversampler's latency: while a setting moves the two only approximately cancel out, which
+// the parameter smoothing keeps inaudible
+//
+pub struct Emphasis {
+    pre: [BiquadFilter; 3],
+    post: [BiquadFilter; 3],
+    /// Tilt, frequency and amount the filters were computed for
+    settings: [f32; 3],
+    sample_rate: f32,
+}
+
+impl Emphasis {
+    pub fn new(sample_rate: f32) -> Self {
+        let flat = || {
+            BiquadFilter::with_parameters(1000.0, sample_rate, EMPHASIS_Q, 0.0, FilterType::PEAK)
+        };
+        Self {
+            pre: [flat(), flat(), flat()],
+            post: [flat(), flat(), flat()],
+            settings: [0.0, 1000.0, 0.0],
+            sample_rate,
+        }
+    }
+
+    /// Only recomputes the filters when the settings changed, it may be called every block
+    pub fn set(&mut self, tilt: f32, frequency: f32, amount: f32) {
+        if [tilt, frequency, amount] == self.settings {
+            return;
+        }
+        self.settings = [tilt, frequency, amount];
+        let frequency = frequency.min(self.sample_rate * 0.45);
+        let filters = [
+            (FilterType::LOWSHELF, BUTTERWORTH_Q, -tilt / 2.0),
+            (FilterType::HIGHSHELF, BUTTERWORTH_Q, tilt / 2.0),
+            (FilterType::PEAK, EMPHASIS_Q, amount),
+        ];
+        for ((pre, post), (filter_type, q, gain_db)) in
+            self.pre.iter_mut().zip(self.post.iter_mut()).zip(filters)
+        {
+            pre.update_coefficients(frequency, self.sample_rate, q, gain_db, filter_type);
+            post.update_coefficients(frequency, self.sample_rate, q, -gain_db, filter_type);
+        }
+    }
+
+    // The cascades also run when flat, so that moving a setting through 0 neither drops their
+    // state nor restarts later from a stale one
+    pub fn emphasize(&mut self, samples: &mut [f32]) {
+        filter_cascade(&mut self.pre, samples);
+    }
+
+    pub fn deemphasize(&mut self, samples: &mut [f32]) {
+        filter_cascade(&mut self.post, samples);
+    }
+
+    pub fn reset(&mut self) {
+        for filter in self.pre.iter_mut().chain(self.post.iter_mut()) {
+            filter.reset();
+        }
+    }
+}
+
+fn filter_cascade(filters: &mut [BiquadFilter], samples: &mut [f32]) {
+    for sample in samples.iter_mut() {
+        for filter in filters.iter_mut() {
+            *sample = filter.filter(sample);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    /// Uniform white noise in [-1, 1)
+    fn noise(len: usize) -> Vec<f32> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 8) as f32 / (1 << 23) as f32 - 1.0
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crossover_bands_sum_to_an_allpass() {
+        for frequency in [100.0, 1000.0, 10000.0] {
+            let mut crossover = Crossover::new(frequency, SAMPLE_RATE);
+            let mut allpass = BiquadFilter::with_parameters(
+                frequency,
+                SAMPLE_RATE,
+                BUTTERWORTH_Q,
+                0.0,
+                FilterType::ALLPASS,
+            );
+            for mut sample in noise(4800) {
+                let (low, high) = crossover.split(sample);
+                let expected = allpass.filter(&mut sample);
+                assert!((low + high - expected).abs() < 1e-4, "{} Hz", frequency);
+            }
+        }
+    }
+
+    /// Gain of a steady sine through the filter, in dB, over whole periods at 60, 1000 and 12000 Hz
+    fn gain_db(filter: &mut impl Filter, frequency: f32) -> f32 {
+        let output: Vec<f32> = (0..4800)
+            .map(|n| {
+                let mut sample = (2.0 * PI * frequency * n as f32 / SAMPLE_RATE).sin();
+                filter.filter(&mut sample)
+            })
+            .collect();
+        let tail = &output[2400..];
+        let rms = (tail.iter().map(|x| x * x).sum::<f32>() / tail.len() as f32).sqrt();
+        20.0 * (rms * 2.0f32.sqrt()).log10()
+    }
+
+    #[test]
+    fn biquad_responses() {
+        // Gains expected at 60 Hz, at the 1 kHz center frequency and at 12 kHz, with a Butterworth
+        // Q and a gain of 6 dB. None stands for a strong attenuation
+        let responses = [
+            (FilterType::LOWPASS, [Some(0.0), Some(-3.01), None]),
+            (FilterType::HIGHPASS, [None, Some(-3.01), Some(0.0)]),
+            (FilterType::BANDPASS, [None, Some(0.0), None]),
+            (FilterType::NOTCH, [Some(0.0), None, Some(0.0)]),
+            (FilterType::ALLPASS, [Some(0.0), Some(0.0), Some(0.0)]),
+            (FilterType::PEAK, [Some(0.0), Some(6.0), Some(0.0)]),
+            (FilterType::LOWSHELF, [Some(6.0), Some(3.0), Some(0.0)]),
+            (FilterType::HIGHSHELF, [Some(0.0), Some(3.0), Some(6.0)]),
+        ];
+        for (filter_type, expected) in responses {
+            for (frequency, expected) in [60.0, 1000.0, 12000.0].into_iter().zip(expected) {
+                let mut filter = BiquadFilter::with_parameters(
+                    1000.0,
+                    SAMPLE_RATE,
+                    BUTTERWORTH_Q,
+                    6.0,
+                    filter_type,
+                );
+                let gain = gain_db(&mut filter, frequency);
+                match expected {
+                    Some(expected) => assert!(
+                        (gain - expected).abs() < 0.2,
+                        "{:?} at {} Hz: {} dB",
+                        filter_type,
+                        frequency,
+                        gain
+                    ),
+                    None => assert!(
+                        gain < -15.0,
+                        "{:?} at {} Hz: {} dB",
+                        filter_type,
+                        frequency,
+                        gain
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deemphasis_undoes_emphasis() {
+        for (tilt, frequency, amount) in [
+            (6.0, 800.0, 12.0),
+            (-12.0, 3000.0, -6.0),
+            (0.0, 1000.0, 0.0),
+        ] {
+            let mut emphasis = Emphasis::new(SAMPLE_RATE);
+            emphasis.set(tilt, frequency, amount);
+            let input = noise(4800);
+            let mut samples = input.clone();
+            for block in samples.chunks_mut(64) {
+                emphasis.emphasize(block);
+                emphasis.deemphasize(block);
+            }
+            let error = samples
+                .iter()
+                .zip(&input)
+                .map(|(sample, input)| (sample - input).abs())
+                .fold(0.0, f32::max);
+            assert!(error < 1e-4, "{} {} {}: {}", tilt, frequency, amount, error);
+        }
+    }
+
+    #[test]
+    fn svf_outputs_sum_to_the_input() {
+        let mut svf = StateVariableFilter::with_q(1000.0, SAMPLE_RATE, 0.7, FilterType::LOWPASS);
+        for (n, sample) in noise(4800).into_iter().enumerate() {
+            // Modulated every sample, the identity holds whatever the coefficients
+            let cutoff = if n % 6 < 3 { 50.0 } else { 15000.0 };
+            svf.set(cutoff, 0.5 + (n % 7) as f32);
+            let outputs = svf.process(sample);
+            let sum = outputs.lowpass + svf.k * outputs.bandpass + outputs.highpass;
+            assert!((sum - sample).abs() < 1e-4, "sample {}", n);
+        }
+    }
+
+    #[test]
+    fn svf_stays_stable_under_modulation() {
+        let mut svf = StateVariableFilter::with_q(1000.0, SAMPLE_RATE, 8.0, FilterType::LOWPASS);
+        let mut peak = 0.0f32;
+        for (n, sample) in noise(48000).into_iter().enumerate() {
+            svf.set(if (n / 3) % 2 == 0 { 50.0 } else { 15000.0 }, 8.0);
+            peak = peak.max(svf.process(sample).lowpass.abs());
+        }
+        assert!(peak < 20.0, "{}", peak);
+    }
+}
@@ -1,16 +1,34 @@
 mod distortions;
 mod editor;
+mod expression;
 mod filters;
+mod mixer;
+mod neural;
 mod oversamplers;
+mod tone;
 mod utils;
 
 use nih_plug::prelude::*;
 use nih_plug_vizia::ViziaState;
-use std::sync::Arc;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
 use crate::{
-    distortions::DistortionType,
-    oversamplers::{NaiveOversampler, Oversampler, Oversampling, BLOCK_SIZE},
+    distortions::{
+        default_custom_curve, sanitize_custom_curve, AdaaOrder, BandSettings, BandSplit, DiodeType,
+        DistortionProcessor, DistortionType, FoldShape, MultibandDistortion, HARMONICS_COUNT,
+        MAX_BANDS, MAX_STAGES,
+    },
+    expression::{ParseError, Program, DEFAULT_EXPRESSION},
+    filters::{Emphasis, Filter, FilterType, OnePoleFilter},
+    mixer::{mix, DelayLine, MixLaw},
+    neural::NeuralModel,
+    oversamplers::{
+        LanczosOversampler, NaiveOversampler, Oversampler, Oversampling, OversamplingFactor,
+        PolyphaseOversampler, Resampler, BLOCK_SIZE, DEFAULT_LANCZOS_RADIUS, MAX_LANCZOS_RADIUS,
+    },
+    tone::{ToneFilter, ToneMode},
     utils::gain_meter_calculator,
 };
 
@@ -21,9 +39,40 @@ use crate::{
 /// The time it takes for the peak meter to decay by 12 dB after switching to complete silence.
 const PEAK_METER_DECAY_MS: f64 = 150.0;
 
+/// Cutoff of the highpass removing the DC offset introduced by asymmetric distortions.
+const DC_BLOCKER_CUTOFF_HZ: i32 = 10;
+
+/// The longest oversampler latency the dry signal can be delayed by, in samples.
+const MAX_DRY_DELAY: usize = 1024;
+
+/// Work done off the audio thread
+enum DistAllTask {
+    /// Read a NEURAL model file
+    LoadNeuralModel(String),
+}
+
 struct DistAll {
     params: Arc<DistAllParams>,
     naive_oversamplers: Vec<NaiveOversampler>,
+    lanczos_oversamplers: Vec<LanczosOversampler>,
+    polyphase_oversamplers: Vec<PolyphaseOversampler>,
+    /// One per channel, runs the distortion at the NEURAL model's rate
+    neural_resamplers: Vec<Resampler>,
+    /// One per channel
+    distortions: Vec<MultibandDistortion>,
+    /// One per channel, around the distortion
+    emphases: Vec<Emphasis>,
+    /// One per channel, after the distortion
+    dc_blockers: Vec<OnePoleFilter>,
+    /// One per channel, after the DC blockers
+    tone_filters: Vec<ToneFilter>,
+    sample_rate: f32,
+    /// Latency currently reported to the host, in samples
+    latency: u32,
+    /// One per channel, delays the dry signal by `latency`
+    dry_delays: Vec<DelayLine>,
+    /// Rate the loaded NEURAL model was trained at
+    neural_sample_rate: Option<f32>,
     peak_meter_pre: Arc<AtomicF32>,
     peak_meter_post: Arc<AtomicF32>,
     peak_meter_decay_weight: f32,
@@ -43,8 +92,287 @@ struct DistAllParams {
     pub post_gain: FloatParam,
     #[id = "oversampler"]
     pub oversampler: EnumParam<Oversampler>,
+    #[id = "oversampling_factor"]
+    pub oversampling_factor: EnumParam<OversamplingFactor>,
+    #[id = "lanczos_radius"]
+    pub lanczos_radius: IntParam,
+    /// First stage of the chain, always enabled
+    #[id = "distortion"]
+    pub distortion: EnumParam<DistortionType>,
+    /// Blended into the first stage by `morph`
+    #[id = "distortion_b"]
+    pub distortion_b: EnumParam<DistortionType>,
+    #[id = "morph"]
+    pub morph: FloatParam,
+    #[nested(id_prefix = "stage_2", group = "Stage 2")]
+    pub stage_2: Arc<StageParams>,
+    #[nested(id_prefix = "stage_3", group = "Stage 3")]
+    pub stage_3: Arc<StageParams>,
+    #[nested(id_prefix = "stage_4", group = "Stage 4")]
+    pub stage_4: Arc<StageParams>,
+    /// With a split, the bands' types replace the first stage's
+    #[id = "band_split"]
+    pub band_split: EnumParam<BandSplit>,
+    #[id = "low_crossover"]
+    pub low_crossover: FloatParam,
+    /// Only used with 3 bands
+    #[id = "high_crossover"]
+    pub high_crossover: FloatParam,
+    #[nested(id_prefix = "band_1", group = "Band 1")]
+    pub band_1: Arc<BandParams>,
+    #[nested(id_prefix = "band_2", group = "Band 2")]
+    pub band_2: Arc<BandParams>,
+    #[nested(id_prefix = "band_3", group = "Band 3")]
+    pub band_3: Arc<BandParams>,
+    /// Pre-emphasis before the distortion, undone after it
+    #[id = "emphasis_tilt"]
+    pub emphasis_tilt: FloatParam,
+    #[id = "emphasis_frequency"]
+    pub emphasis_frequency: FloatParam,
+    #[id = "emphasis_amount"]
+    pub emphasis_amount: FloatParam,
+    /// Filter after the distortion
+    #[id = "tone_mode"]
+    pub tone_mode: EnumParam<ToneMode>,
+    #[id = "tone_cutoff"]
+    pub tone_cutoff: FloatParam,
+    #[id = "tone_resonance"]
+    pub tone_resonance: FloatParam,
+    /// Octaves the cutoff moves by, following the envelope of the input
+    #[id = "tone_envelope"]
+    pub tone_envelope: FloatParam,
+    #[id = "adaa"]
+    pub adaa: EnumParam<AdaaOrder>,
+    #[nested(group = "Curve")]
+    pub curve: Arc<CurveParams>,
+    /// Control points of the CUSTOM curve, sorted by input, edited in the editor
+    #[persist = "custom-curve"]
+    pub custom_curve: Arc<RwLock<Vec<(f32, f32)>>>,
+    /// Set by the editor whenever it moves a control point of `custom_curve`
+    pub custom_curve_changed: Arc<AtomicBool>,
+    /// Source of the EXPRESSION formula, always a valid one
+    #[persist = "expression"]
+    pub expression: Arc<RwLock<String>>,
+    /// `expression` compiled, see `set_expression`
+    pub expression_program: Arc<RwLock<Program>>,
+    pub expression_changed: Arc<AtomicBool>,
+    /// Path of the NEURAL model file, only set once the model has been loaded
+    #[persist = "neural-model"]
+    pub neural_model_path: Arc<RwLock<String>>,
+    /// One model per stage of each band of each channel, loaded by `DistAllTask::LoadNeuralModel`
+    /// and swapped with the previous ones by the audio thread
+    pub neural_models: Arc<Mutex<Vec<Option<NeuralModel>>>>,
+    pub neural_models_changed: Arc<AtomicBool>,
+    /// Description of the loaded model, or why the last one couldn't be loaded
+    pub neural_model_status: Arc<RwLock<String>>,
+    /// Rate the loaded model was trained at and rate the NEURAL stages actually run at, 0 while
+    /// no model is used, published by the audio thread for the editor
+    pub neural_model_rate: Arc<AtomicF32>,
+    pub neural_processing_rate: Arc<AtomicF32>,
+    #[id = "mix"]
+    pub mix: FloatParam,
+    #[id = "mix_law"]
+    pub mix_law: EnumParam<MixLaw>,
+}
+
+/// A distortion stage following the first one
+#[derive(Params)]
+struct StageParams {
+    #[id = "enabled"]
+    pub enabled: BoolParam,
+    #[id = "distortion"]
+    pub distortion: EnumParam<DistortionType>,
+    #[id = "drive"]
+    pub drive: FloatParam,
+}
+
+impl StageParams {
+    fn new(stage: usize) -> Self {
+        Self {
+            enabled: BoolParam::new(format!("Stage {}", stage), false),
+            distortion: EnumParam::new(format!("Stage {} Distortion", stage), DistortionType::HARD),
+            // Gain going into the stage, the output of the previous stage being around full scale
+            drive: FloatParam::new(
+                format!("Stage {} Drive", stage),
+                util::db_to_gain(0.0),
+                FloatRange::Skewed {
+                    min: util::db_to_gain(-30.0),
+                    max: util::db_to_gain(30.0),
+                    factor: FloatRange::gain_skew_factor(-30.0, 30.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_unit(" dB")
+            .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
+            .with_string_to_value(formatters::s2v_f32_gain_to_db()),
+        }
+    }
+}
+
+/// A frequency band, when the signal is split
+#[derive(Params)]
+struct BandParams {
     #[id = "distortion"]
     pub distortion: EnumParam<DistortionType>,
+    #[id = "drive"]
+    pub drive: FloatParam,
+    #[id = "mix"]
+    pub mix: FloatParam,
+    #[id = "solo"]
+    pub solo: BoolParam,
+    #[id = "mute"]
+    pub mute: BoolParam,
+}
+
+impl BandParams {
+    fn new(band: usize) -> Self {
+        Self {
+            distortion: EnumParam::new(format!("Band {} Distortion", band), DistortionType::SOFT),
+            // Gain going into the band, on top of the pre gain
+            drive: FloatParam::new(
+                format!("Band {} Drive", band),
+                util::db_to_gain(0.0),
+                FloatRange::Skewed {
+                    min: util::db_to_gain(-30.0),
+                    max: util::db_to_gain(30.0),
+                    factor: FloatRange::gain_skew_factor(-30.0, 30.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_unit(" dB")
+            .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
+            .with_string_to_value(formatters::s2v_f32_gain_to_db()),
+            mix: FloatParam::new(
+                format!("Band {} Mix", band),
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            solo: BoolParam::new(format!("Band {} Solo", band), false),
+            mute: BoolParam::new(format!("Band {} Mute", band), false),
+        }
+    }
+}
+
+fn frequency_param(name: &str, default: f32) -> FloatParam {
+    FloatParam::new(
+        name,
+        default,
+        FloatRange::Skewed {
+            min: 20.0,
+            max: 20000.0,
+            factor: FloatRange::skew_factor(-2.0),
+        },
+    )
+    .with_smoother(SmoothingStyle::Logarithmic(50.0))
+    .with_unit(" Hz")
+    .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
+    .with_string_to_value(formatters::s2v_f32_hz_then_khz())
+}
+
+fn emphasis_gain_param(name: &str) -> FloatParam {
+    FloatParam::new(
+        name,
+        0.0,
+        FloatRange::Linear {
+            min: -18.0,
+            max: 18.0,
+        },
+    )
+    .with_smoother(SmoothingStyle::Linear(50.0))
+    .with_step_size(0.1)
+    .with_unit(" dB")
+}
+
+/// Parameters specific to some of the distortion types
+#[derive(Params)]
+struct CurveParams {
+    #[id = "knee"]
+    pub knee: FloatParam,
+    #[id = "bias"]
+    pub bias: FloatParam,
+    #[id = "fold_threshold"]
+    pub fold_threshold: FloatParam,
+    #[id = "fold_symmetry"]
+    pub fold_symmetry: FloatParam,
+    #[id = "fold_shape"]
+    pub fold_shape: EnumParam<FoldShape>,
+    #[id = "bit_depth"]
+    pub bit_depth: FloatParam,
+    #[id = "dither"]
+    pub dither: BoolParam,
+    #[id = "downsample_rate"]
+    pub downsample_rate: FloatParam,
+    #[id = "rectify"]
+    pub rectify: FloatParam,
+    #[id = "harmonic_2"]
+    pub harmonic_2: FloatParam,
+    #[id = "harmonic_3"]
+    pub harmonic_3: FloatParam,
+    #[id = "harmonic_4"]
+    pub harmonic_4: FloatParam,
+    #[id = "harmonic_5"]
+    pub harmonic_5: FloatParam,
+    #[id = "harmonic_6"]
+    pub harmonic_6: FloatParam,
+    #[id = "harmonic_7"]
+    pub harmonic_7: FloatParam,
+    #[id = "harmonic_8"]
+    pub harmonic_8: FloatParam,
+    #[id = "tape_drive"]
+    pub tape_drive: FloatParam,
+    #[id = "tape_saturation"]
+    pub tape_saturation: FloatParam,
+    #[id = "tape_width"]
+    pub tape_width: FloatParam,
+    #[id = "diode_type"]
+    pub diode_type: EnumParam<DiodeType>,
+    #[id = "diode_capacitance"]
+    pub diode_capacitance: FloatParam,
+    #[id = "tube_plate_voltage"]
+    pub tube_plate_voltage: FloatParam,
+    #[id = "tube_bias"]
+    pub tube_bias: FloatParam,
+}
+
+impl CurveParams {
+    /// HARMONICS: level of the harmonics 2 to 8 relative to the fundamental, negative levels
+    /// invert their phase
+    fn harmonic(harmonic: usize) -> FloatParam {
+        FloatParam::new(
+            format!("Harmonic {}", harmonic),
+            0.0,
+            FloatRange::Linear {
+                min: -1.0,
+                max: 1.0,
+            },
+        )
+        .with_smoother(SmoothingStyle::Linear(50.0))
+        .with_value_to_string(formatters::v2s_f32_rounded(2))
+    }
+
+    fn percentage(name: &str) -> FloatParam {
+        FloatParam::new(name, 0.5, FloatRange::Linear { min: 0.0, max: 1.0 })
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage())
+    }
+
+    fn harmonics(&self) -> [&FloatParam; HARMONICS_COUNT] {
+        [
+            &self.harmonic_2,
+            &self.harmonic_3,
+            &self.harmonic_4,
+            &self.harmonic_5,
+            &self.harmonic_6,
+            &self.harmonic_7,
+            &self.harmonic_8,
+        ]
+    }
 }
 
 impl Default for DistAll {
@@ -52,6 +380,17 @@ impl Default for DistAll {
         Self {
             params: Arc::new(DistAllParams::default()),
             naive_oversamplers: vec![],
+            lanczos_oversamplers: vec![],
+            polyphase_oversamplers: vec![],
+            neural_resamplers: vec![],
+            distortions: vec![],
+            emphases: vec![],
+            dc_blockers: vec![],
+            tone_filters: vec![],
+            sample_rate: 44100.0,
+            latency: 0,
+            dry_delays: vec![],
+            neural_sample_rate: None,
             peak_meter_pre: Arc::new(AtomicF32::new(util::MINUS_INFINITY_DB)),
             peak_meter_post: Arc::new(AtomicF32::new(util::MINUS_INFINITY_DB)),
             peak_meter_decay_weight: 1.0,
@@ -107,7 +446,491 @@ impl Default for DistAllParams {
             .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
             .with_string_to_value(formatters::s2v_f32_gain_to_db()),
             oversampler: EnumParam::new("Oversampler", Oversampler::None),
+            // Only used by the PolyphaseOversampler, the other ones are fixed x2
+            oversampling_factor: EnumParam::new("Oversampling Factor", OversamplingFactor::X4),
+            // Only used by the LanczosOversampler: larger kernels filter better but cost more CPU
+            // and add latency. Changing it clears the filters and the latency, so it can't be
+            // automated
+            lanczos_radius: IntParam::new(
+                "Lanczos Radius",
+                DEFAULT_LANCZOS_RADIUS as i32,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_LANCZOS_RADIUS as i32,
+                },
+            )
+            .non_automatable(),
             distortion: EnumParam::new("Distortion", DistortionType::SOFT),
+            distortion_b: EnumParam::new("Distortion B", DistortionType::HARD),
+            morph: FloatParam::new("Morph", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(50.0))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            stage_2: Arc::new(StageParams::new(2)),
+            stage_3: Arc::new(StageParams::new(3)),
+            stage_4: Arc::new(StageParams::new(4)),
+            band_split: EnumParam::new("Band Split", BandSplit::Off),
+            low_crossover: frequency_param("Low Crossover", 200.0),
+            high_crossover: frequency_param("High Crossover", 2000.0),
+            band_1: Arc::new(BandParams::new(1)),
+            band_2: Arc::new(BandParams::new(2)),
+            band_3: Arc::new(BandParams::new(3)),
+            emphasis_tilt: emphasis_gain_param("Emphasis Tilt"),
+            emphasis_frequency: frequency_param("Emphasis Frequency", 1000.0),
+            emphasis_amount: emphasis_gain_param("Emphasis Amount"),
+            tone_mode: EnumParam::new("Tone", ToneMode::Off),
+            tone_cutoff: frequency_param("Tone Cutoff", 1000.0),
+            tone_resonance: FloatParam::new(
+                "Tone Resonance",
+                0.7,
+                FloatRange::Skewed {
+                    min: 0.5,
+                    max: 10.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            tone_envelope: FloatParam::new(
+                "Tone Envelope",
+                0.0,
+                FloatRange::Linear {
+                    min: -4.0,
+                    max: 4.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01)
+            .with_unit(" oct"),
+            adaa: EnumParam::new("ADAA", AdaaOrder::Off),
+            curve: Arc::new(CurveParams::default()),
+            custom_curve: Arc::new(RwLock::new(default_custom_curve())),
+            custom_curve_changed: Arc::new(AtomicBool::new(true)),
+            expression: Arc::new(RwLock::new(DEFAULT_EXPRESSION.to_string())),
+            expression_program: Arc::new(RwLock::new(
+                Program::compile(DEFAULT_EXPRESSION).unwrap(),
+            )),
+            expression_changed: Arc::new(AtomicBool::new(true)),
+            neural_model_path: Arc::new(RwLock::new(String::new())),
+            neural_models: Arc::new(Mutex::new(vec![])),
+            neural_models_changed: Arc::new(AtomicBool::new(false)),
+            neural_model_status: Arc::new(RwLock::new("No model loaded".to_string())),
+            neural_model_rate: Arc::new(AtomicF32::new(0.0)),
+            neural_processing_rate: Arc::new(AtomicF32::new(0.0)),
+            // Fully wet by default, which is what DistAll did before this parameter existed
+            mix: FloatParam::new("Mix", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(50.0))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            mix_law: EnumParam::new("Mix Law", MixLaw::EqualPower),
+        }
+    }
+}
+
+impl Default for CurveParams {
+    fn default() -> Self {
+        Self {
+            // KNEE: 0% is the SOFT curve, 100% the HARD one
+            knee: FloatParam::new("Knee", 0.5, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(50.0))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            // All types: offset added before the curve, so that positive and negative half-waves
+            // are not clipped the same way, producing even harmonics
+            bias: FloatParam::new(
+                "Bias",
+                0.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            // FOLD: the signal is reflected back when it exceeds the threshold
+            fold_threshold: FloatParam::new(
+                "Fold Threshold",
+                0.5,
+                FloatRange::Linear {
+                    min: 0.05,
+                    max: 1.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            fold_symmetry: FloatParam::new(
+                "Fold Symmetry",
+                0.0,
+                FloatRange::Linear {
+                    min: -0.9,
+                    max: 0.9,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            fold_shape: EnumParam::new("Fold Shape", FoldShape::Triangle),
+            // BITCRUSH: fractional depths sweep smoothly between the integer ones
+            bit_depth: FloatParam::new(
+                "Bit Depth",
+                8.0,
+                FloatRange::Linear {
+                    min: 1.0,
+                    max: 16.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_unit(" bits")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            dither: BoolParam::new("TPDF Dither", false),
+            // DOWNSAMPLE: rate at which the input is sampled and held
+            downsample_rate: FloatParam::new(
+                "Downsample Rate",
+                8000.0,
+                FloatRange::Skewed {
+                    min: 100.0,
+                    max: 48000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(1))
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            // RECTIFY: 50% is a half-wave rectifier and 100% a full-wave one
+            rectify: FloatParam::new("Rectify", 0.5, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(50.0))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            harmonic_2: CurveParams::harmonic(2),
+            harmonic_3: CurveParams::harmonic(3),
+            harmonic_4: CurveParams::harmonic(4),
+            harmonic_5: CurveParams::harmonic(5),
+            harmonic_6: CurveParams::harmonic(6),
+            harmonic_7: CurveParams::harmonic(7),
+            harmonic_8: CurveParams::harmonic(8),
+            // TAPE: how hard the tape is hit, how low it saturates and how wide its hysteresis
+            // loop is
+            tape_drive: CurveParams::percentage("Tape Drive"),
+            tape_saturation: CurveParams::percentage("Tape Saturation"),
+            tape_width: CurveParams::percentage("Tape Width"),
+            diode_type: EnumParam::new("Diode Type", DiodeType::Silicon),
+            // DIODE: the lowpass before the diodes, from 72 kHz down to 720 Hz
+            diode_capacitance: FloatParam::new(
+                "Diode Capacitance",
+                10.0,
+                FloatRange::Skewed {
+                    min: 1.0,
+                    max: 100.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(50.0))
+            .with_unit(" nF")
+            .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            // TUBE: supply voltage and grid bias of the triode, lower supplies and biases closer
+            // to 0 V clip earlier and more asymmetrically
+            tube_plate_voltage: FloatParam::new(
+                "Tube Plate Voltage",
+                250.0,
+                FloatRange::Linear {
+                    min: 100.0,
+                    max: 400.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_unit(" V")
+            .with_value_to_string(formatters::v2s_f32_rounded(0)),
+            tube_bias: FloatParam::new(
+                "Tube Bias",
+                -1.5,
+                FloatRange::Linear {
+                    min: -4.0,
+                    max: -0.5,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_unit(" V")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+        }
+    }
+}
+
+impl DistAllParams {
+    /// Compile a new EXPRESSION formula and hand it to the audio thread. Compiling allocates, so
+    /// this is meant for the GUI thread. An invalid formula leaves the current one in place.
+    pub fn set_expression(&self, source: &str) -> Result<(), ParseError> {
+        let program = Program::compile(source)?;
+        *self.expression.write().unwrap() = source.to_string();
+        *self.expression_program.write().unwrap() = program;
+        self.expression_changed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stages 2 to 4
+    fn stages(&self) -> [&StageParams; MAX_STAGES - 1] {
+        [&self.stage_2, &self.stage_3, &self.stage_4]
+    }
+
+    fn bands(&self) -> [&BandParams; MAX_BANDS] {
+        [&self.band_1, &self.band_2, &self.band_3]
+    }
+
+    /// Types of the enabled stages: the first stage of every band, then the following ones
+    fn distortion_types(&self) -> impl Iterator<Item = DistortionType> + '_ {
+        let split = self.band_split.value();
+        let first_stages = match split {
+            BandSplit::Off => 0,
+            split => split.bands(),
+        };
+        (split == BandSplit::Off)
+            .then(|| self.distortion.value())
+            .into_iter()
+            .chain(
+                self.bands()
+                    .into_iter()
+                    .take(first_stages)
+                    .map(|band| band.distortion.value()),
+            )
+            .chain(
+                self.stages()
+                    .into_iter()
+                    .filter(|stage| stage.enabled.value())
+                    .map(|stage| stage.distortion.value()),
+            )
+    }
+
+    /// Number of stages a sample goes through that delay it with ADAA, whatever the number of
+    /// bands. The first stage counts when any band or the morph target uses it, the bands are
+    /// aligned on the slowest one
+    fn adaa_stage_count(&self) -> usize {
+        let first_stage = match self.band_split.value() {
+            BandSplit::Off => self.distortion.value().uses_adaa(),
+            split => self
+                .bands()
+                .into_iter()
+                .take(split.bands())
+                .any(|band| band.distortion.value().uses_adaa()),
+        } || self.distortion_b.value().uses_adaa();
+        usize::from(first_stage)
+            + self
+                .stages()
+                .into_iter()
+                .filter(|stage| stage.enabled.value() && stage.distortion.value().uses_adaa())
+                .count()
+    }
+
+    /// Load a NEURAL model file, one copy per stage of each band of each channel. Meant for the
+    /// background thread, it is also where the previous models get deallocated.
+    fn load_neural_model(&self, path: &str) {
+        let model = fs::read_to_string(path)
+            .map_err(|error| error.to_string())
+            .and_then(|json| NeuralModel::from_json(&json));
+        match model {
+            Ok(model) => {
+                *self.neural_model_status.write().unwrap() = model.to_string();
+                *self.neural_model_path.write().unwrap() = path.to_string();
+                *self.neural_models.lock().unwrap() = (0..2 * MAX_BANDS * MAX_STAGES)
+                    .map(|_| Some(model.clone()))
+                    .collect();
+                self.neural_models_changed.store(true, Ordering::Relaxed);
+            }
+            Err(error) => {
+                *self.neural_model_status.write().unwrap() = format!("{}: {}", path, error);
+            }
+        }
+    }
+}
+
+impl DistAll {
+    /// Types of the enabled stages, and the morph target even when it is not heard, so that
+    /// automating the morph never changes the latency
+    fn distortion_types(&self) -> impl Iterator<Item = DistortionType> + '_ {
+        self.params
+            .distortion_types()
+            .chain(std::iter::once(self.params.distortion_b.value()))
+    }
+
+    /// The oversampler and factor actually used: the lo-fi types rely on aliasing, and NEURAL runs
+    /// at the rate its model was trained at, see `neural_rate`. Any enabled stage of these types
+    /// decides for the whole chain
+    fn oversampling(&self) -> (Oversampler, OversamplingFactor) {
+        let factor = self.params.oversampling_factor.value();
+        if self.distortion_types().any(|distortion_type| {
+            distortion_type.bypasses_oversampling() || distortion_type == DistortionType::NEURAL
+        }) {
+            (Oversampler::None, factor)
+        } else {
+            (self.params.oversampler.value(), factor)
+        }
+    }
+
+    /// The rate the chain is resampled to when a NEURAL stage runs a model trained at another rate
+    /// than the host's
+    fn neural_rate(&self) -> Option<f32> {
+        if self
+            .distortion_types()
+            .any(DistortionType::bypasses_oversampling)
+            || !self
+                .distortion_types()
+                .any(|distortion_type| distortion_type == DistortionType::NEURAL)
+        {
+            return None;
+        }
+        self.neural_sample_rate
+            .filter(|&rate| rate != self.sample_rate)
+    }
+
+    /// Apply the oversampling and anti-aliasing parameters to the per channel processors and
+    /// return the latency they add
+    fn update_processors(
+        &mut self,
+        (oversampler, oversampling_factor): (Oversampler, OversamplingFactor),
+        neural_rate: Option<f32>,
+        adaa_order: AdaaOrder,
+    ) -> u32 {
+        let lanczos_radius = self.params.lanczos_radius.value() as usize;
+        for oversampler in &mut self.lanczos_oversamplers {
+            oversampler.set_radius(lanczos_radius);
+        }
+        for oversampler in &mut self.polyphase_oversamplers {
+            oversampler.set_factor(oversampling_factor);
+        }
+        if let Some(rate) = neural_rate {
+            for resampler in &mut self.neural_resamplers {
+                resampler.set_rate(rate);
+            }
+        }
+
+        let (latency, sample_rate) = match oversampler {
+            Oversampler::None if neural_rate.is_some() => (
+                self.neural_resamplers[0].latency(),
+                self.neural_resamplers[0].rate(),
+            ),
+            // Only whole samples can be compensated: at the host's rate the first order delays
+            // (half a sample per ADAA stage) are rounded down, and once oversampled they are
+            // negligible
+            Oversampler::None => (
+                (adaa_order.delay() * self.params.adaa_stage_count() as f32).floor() as u32,
+                self.sample_rate,
+            ),
+            Oversampler::NaiveOversampler => (
+                self.naive_oversamplers[0].latency(),
+                self.sample_rate * self.naive_oversamplers[0].ratio() as f32,
+            ),
+            Oversampler::LanczosOversampler => (
+                self.lanczos_oversamplers[0].latency(),
+                self.sample_rate * self.lanczos_oversamplers[0].ratio() as f32,
+            ),
+            Oversampler::PolyphaseOversampler => (
+                self.polyphase_oversamplers[0].latency(),
+                self.sample_rate * self.polyphase_oversamplers[0].ratio() as f32,
+            ),
+        };
+
+        // Shown by the editor, the model may run at another rate than its own when a lo-fi type
+        // prevents resampling or when the rates are too far apart
+        let neural_used = self
+            .distortion_types()
+            .any(|distortion_type| distortion_type == DistortionType::NEURAL);
+        let (model_rate, processing_rate) = match self.neural_sample_rate {
+            Some(model_rate) if neural_used => (model_rate, sample_rate),
+            _ => (0.0, 0.0),
+        };
+        self.params
+            .neural_model_rate
+            .store(model_rate, Ordering::Relaxed);
+        self.params
+            .neural_processing_rate
+            .store(processing_rate, Ordering::Relaxed);
+
+        for distortion in &mut self.distortions {
+            distortion.set_sample_rate(sample_rate);
+            distortion.set_adaa_order(adaa_order);
+        }
+        latency
+    }
+
+    /// Rebuild the CUSTOM lookup tables when the editor or a restored state changed the control
+    /// points. Never blocks: while the editor holds the lock, the update waits for the next buffer
+    fn update_custom_curve(&mut self) {
+        if !self
+            .params
+            .custom_curve_changed
+            .swap(false, Ordering::Relaxed)
+        {
+            return;
+        }
+        match self.params.custom_curve.try_read() {
+            Ok(points) => {
+                for stage in self
+                    .distortions
+                    .iter_mut()
+                    .flat_map(MultibandDistortion::stages_mut)
+                {
+                    stage.set_custom_curve(&points);
+                }
+            }
+            Err(_) => self
+                .params
+                .custom_curve_changed
+                .store(true, Ordering::Relaxed),
+        }
+    }
+
+    /// Same as `update_custom_curve` for the NEURAL models, the previous ones are handed back to
+    /// be deallocated by the next load
+    fn update_neural_models(&mut self) {
+        if !self
+            .params
+            .neural_models_changed
+            .swap(false, Ordering::Relaxed)
+        {
+            return;
+        }
+        match self.params.neural_models.try_lock() {
+            Ok(mut models) => {
+                let stages = self
+                    .distortions
+                    .iter_mut()
+                    .flat_map(MultibandDistortion::stages_mut);
+                for (stage, model) in stages.zip(models.iter_mut()) {
+                    stage.swap_neural_model(model);
+                }
+                self.neural_sample_rate = self.distortions[0].neural_sample_rate();
+            }
+            Err(_) => self
+                .params
+                .neural_models_changed
+                .store(true, Ordering::Relaxed),
+        }
+    }
+
+    /// Same as `update_custom_curve` for the compiled EXPRESSION
+    fn update_expression(&mut self) {
+        if !self
+            .params
+            .expression_changed
+            .swap(false, Ordering::Relaxed)
+        {
+            return;
+        }
+        match self.params.expression_program.try_read() {
+            Ok(program) => {
+                for stage in self
+                    .distortions
+                    .iter_mut()
+                    .flat_map(MultibandDistortion::stages_mut)
+                {
+                    stage.set_expression(&program);
+                }
+            }
+            Err(_) => self
+                .params
+                .expression_changed
+                .store(true, Ordering::Relaxed),
         }
     }
 }
@@ -147,15 +970,23 @@ impl Plugin for DistAll {
     // More advanced plugins can use this to run expensive background tasks. See the field's
     // documentation for more information. `()` means that the plugin does not have any background
     // tasks.
-    type BackgroundTask = ();
+    type BackgroundTask = DistAllTask;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
-    fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let params = self.params.clone();
+        Box::new(move |task| match task {
+            DistAllTask::LoadNeuralModel(path) => params.load_neural_model(&path),
+        })
+    }
+
+    fn editor(&mut self, async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         editor::create(
             self.params.clone(),
+            async_executor,
             self.peak_meter_pre.clone(),
             self.peak_meter_post.clone(),
             self.params.editor_state.clone(),
@@ -175,10 +1006,80 @@ impl Plugin for DistAll {
             .powf((_buffer_config.sample_rate as f64 * PEAK_METER_DECAY_MS / 1000.0).recip())
             as f32;
 
-        self.naive_oversamplers
-            .push(NaiveOversampler::new(_buffer_config.sample_rate));
-        self.naive_oversamplers
-            .push(NaiveOversampler::new(_buffer_config.sample_rate));
+        // Rebuilt from scratch, the host may initialize again with another sample rate
+        let sample_rate = _buffer_config.sample_rate;
+        let channels = 0..2;
+        self.naive_oversamplers = channels
+            .clone()
+            .map(|_| NaiveOversampler::new(sample_rate))
+            .collect();
+        self.lanczos_oversamplers = channels
+            .clone()
+            .map(|_| LanczosOversampler::new(sample_rate))
+            .collect();
+        self.polyphase_oversamplers = channels
+            .clone()
+            .map(|_| PolyphaseOversampler::new(sample_rate))
+            .collect();
+        self.sample_rate = sample_rate;
+        // One seed per channel, so that the channels' noises are not correlated
+        self.distortions = channels
+            .clone()
+            .map(|channel| MultibandDistortion::new(sample_rate, channel))
+            .collect();
+        self.emphases = channels
+            .clone()
+            .map(|_| Emphasis::new(sample_rate))
+            .collect();
+        self.dc_blockers = channels
+            .clone()
+            .map(|_| {
+                OnePoleFilter::new(
+                    DC_BLOCKER_CUTOFF_HZ,
+                    sample_rate as i32,
+                    FilterType::HIGHPASS,
+                )
+            })
+            .collect();
+        self.tone_filters = channels
+            .clone()
+            .map(|_| ToneFilter::new(sample_rate))
+            .collect();
+        self.neural_resamplers = channels
+            .clone()
+            .map(|_| Resampler::new(sample_rate))
+            .collect();
+        self.dry_delays = channels.map(|_| DelayLine::new(MAX_DRY_DELAY)).collect();
+
+        // Reloaded from the file, which may have changed since the state was saved
+        let neural_model_path = self.params.neural_model_path.read().unwrap().clone();
+        if !neural_model_path.is_empty() {
+            _context.execute(DistAllTask::LoadNeuralModel(neural_model_path));
+        }
+        self.update_neural_models();
+
+        self.latency = self.update_processors(
+            self.oversampling(),
+            self.neural_rate(),
+            self.params.adaa.value(),
+        );
+        _context.set_latency_samples(self.latency);
+        for dry_delay in &mut self.dry_delays {
+            dry_delay.set_delay(self.latency as usize);
+        }
+
+        // Also called after a state has been restored, whose points may not be valid anymore
+        sanitize_custom_curve(&mut self.params.custom_curve.write().unwrap());
+        self.params
+            .custom_curve_changed
+            .store(true, Ordering::Relaxed);
+        self.update_custom_curve();
+        // The restored formula was valid when it was saved
+        let expression = self.params.expression.read().unwrap().clone();
+        if self.params.set_expression(&expression).is_err() {
+            self.params.set_expression(DEFAULT_EXPRESSION).unwrap();
+        }
+        self.update_expression();
         true
     }
 
@@ -188,6 +1089,27 @@ impl Plugin for DistAll {
         for oversampler in &mut self.naive_oversamplers {
             oversampler.reset()
         }
+        for oversampler in &mut self.lanczos_oversamplers {
+            oversampler.reset()
+        }
+        for oversampler in &mut self.polyphase_oversamplers {
+            oversampler.reset()
+        }
+        for distortion in &mut self.distortions {
+            distortion.reset()
+        }
+        for emphasis in &mut self.emphases {
+            emphasis.reset()
+        }
+        for dc_blocker in &mut self.dc_blockers {
+            dc_blocker.reset()
+        }
+        for tone_filter in &mut self.tone_filters {
+            tone_filter.reset()
+        }
+        for dry_delay in &mut self.dry_delays {
+            dry_delay.reset()
+        }
     }
 
     fn process(
@@ -205,38 +1127,199 @@ impl Plugin for DistAll {
             )
         }
 
+        // The oversampling and anti-aliasing settings are only updated once per buffer since they
+        // may change the latency reported to the host, the lo-fi types always run unoversampled
+        self.update_neural_models();
+        let oversampling = self.oversampling();
+        let oversampler_type = oversampling.0;
+        let neural_rate = self.neural_rate();
+        let adaa_order = self.params.adaa.value();
+        let latency = self.update_processors(oversampling, neural_rate, adaa_order);
+        if latency != self.latency {
+            self.latency = latency;
+            _context.set_latency_samples(latency);
+            for dry_delay in &mut self.dry_delays {
+                dry_delay.set_delay(latency as usize);
+            }
+        }
+        self.update_custom_curve();
+        self.update_expression();
+        let mix_law = self.params.mix_law.value();
+        let mut dry_block = [0.0; BLOCK_SIZE];
+
         for (_, mut block) in buffer.iter_blocks(BLOCK_SIZE) {
             // Smoothing is optionally built into the parameters themselves
             let pre_gain: f32 = self.params.pre_gain.smoothed.next();
             let post_gain: f32 = self.params.post_gain.smoothed.next();
+            let (dry_gain, wet_gain) = mix_law.gains(self.params.mix.smoothed.next());
+
+            let knee = self.params.curve.knee.smoothed.next();
+            let bias = self.params.curve.bias.smoothed.next();
+            let fold_threshold = self.params.curve.fold_threshold.smoothed.next();
+            let fold_symmetry = self.params.curve.fold_symmetry.smoothed.next();
+            let fold_shape = self.params.curve.fold_shape.value();
+            let bit_depth = self.params.curve.bit_depth.smoothed.next();
+            let dither = self.params.curve.dither.value();
+            let downsample_rate = self.params.curve.downsample_rate.smoothed.next();
+            let rectify = self.params.curve.rectify.smoothed.next();
+            let harmonics = self
+                .params
+                .curve
+                .harmonics()
+                .map(|harmonic| harmonic.smoothed.next());
+            let tape_drive = self.params.curve.tape_drive.smoothed.next();
+            let tape_saturation = self.params.curve.tape_saturation.smoothed.next();
+            let tape_width = self.params.curve.tape_width.smoothed.next();
+            let diode_type = self.params.curve.diode_type.value();
+            let diode_capacitance = self.params.curve.diode_capacitance.smoothed.next();
+            let tube_plate_voltage = self.params.curve.tube_plate_voltage.smoothed.next();
+            let tube_bias = self.params.curve.tube_bias.smoothed.next();
+
+            let mut stages = [(self.params.distortion.value(), true, 1.0); MAX_STAGES];
+            for (stage, params) in stages[1..].iter_mut().zip(self.params.stages()) {
+                *stage = (
+                    params.distortion.value(),
+                    params.enabled.value(),
+                    params.drive.smoothed.next(),
+                );
+            }
+            let distortion_b = self.params.distortion_b.value();
+            let morph = self.params.morph.smoothed.next();
 
-            let oversampler_type = self.params.oversampler.value();
-            let distortion_type = self.params.distortion.value().function();
+            let band_split = self.params.band_split.value();
+            let low_crossover = self.params.low_crossover.smoothed.next();
+            let high_crossover = self.params.high_crossover.smoothed.next();
+            let band_params = self.params.bands();
+            let soloed = band_params[..band_split.bands()]
+                .iter()
+                .any(|band| band.solo.value());
+            let bands = band_params.map(|band| {
+                let (dry_gain, wet_gain) = mix_law.gains(band.mix.smoothed.next());
+                let settings = BandSettings {
+                    drive: band.drive.smoothed.next(),
+                    dry_gain,
+                    wet_gain,
+                    audible: !band.mute.value() && (!soloed || band.solo.value()),
+                };
+                (band.distortion.value(), settings)
+            });
+            let emphasis_tilt = self.params.emphasis_tilt.smoothed.next();
+            let emphasis_frequency = self.params.emphasis_frequency.smoothed.next();
+            let emphasis_amount = self.params.emphasis_amount.smoothed.next();
+            let tone_mode = self.params.tone_mode.value();
+            let tone_cutoff = self.params.tone_cutoff.smoothed.next();
+            let tone_resonance = self.params.tone_resonance.smoothed.next();
+            let tone_envelope = self.params.tone_envelope.smoothed.next();
             let channels = block.channels();
 
             for channel_index in 0..channels {
+                let dry = &mut dry_block[..block.samples()];
+                dry.copy_from_slice(block.get_mut(channel_index).unwrap());
+                self.dry_delays[channel_index].process(dry);
+
+                let distortion = &mut self.distortions[channel_index];
+                distortion.set_split(band_split, low_crossover, high_crossover);
+                for (band_index, &(_, settings)) in bands.iter().enumerate() {
+                    distortion.set_band(band_index, settings);
+                }
+                for (band, &(band_type, _)) in distortion.bands_mut().zip(bands.iter()) {
+                    for (index, &(distortion_type, enabled, drive)) in stages.iter().enumerate() {
+                        let distortion_type = match (index, band_split) {
+                            (0, BandSplit::Off) | (1.., _) => distortion_type,
+                            (0, _) => band_type,
+                        };
+                        band.set_stage(index, distortion_type, enabled, drive);
+                    }
+                    band.set_morph(distortion_b, morph);
+                }
+                for stage in distortion.stages_mut() {
+                    stage.set_knee(knee);
+                    stage.set_bias(bias);
+                    stage.set_fold(fold_threshold, fold_symmetry, fold_shape);
+                    stage.set_bit_depth(bit_depth, dither);
+                    stage.set_downsample_rate(downsample_rate);
+                    stage.set_rectify(rectify);
+                    stage.set_harmonics(harmonics);
+                    stage.set_tape(tape_drive, tape_saturation, tape_width);
+                    stage.set_diode(diode_type, diode_capacitance);
+                    stage.set_tube(tube_plate_voltage, tube_bias);
+                }
+
+                // The emphasis is linear, it runs at the host's rate around the oversampler
+                let emphasis = &mut self.emphases[channel_index];
+                emphasis.set(emphasis_tilt, emphasis_frequency, emphasis_amount);
+                emphasis.emphasize(block.get_mut(channel_index).unwrap());
+
                 match oversampler_type {
+                    Oversampler::None if neural_rate.is_some() => {
+                        self.neural_resamplers[channel_index].process(
+                            block.get_mut(channel_index).unwrap(),
+                            distortion,
+                            pre_gain,
+                            post_gain,
+                        );
+                    }
                     Oversampler::None => {
-                        distortion_type(pre_gain, post_gain, block.get_mut(channel_index).unwrap());
+                        distortion.process(
+                            pre_gain,
+                            post_gain,
+                            block.get_mut(channel_index).unwrap(),
+                        );
                     }
                     Oversampler::NaiveOversampler => {
                         match channel_index {
                             0 => self.naive_oversamplers[0].process(
                                 block.get_mut(channel_index).unwrap(),
-                                distortion_type,
+                                distortion,
                                 pre_gain,
                                 post_gain,
                             ),
                             1 => self.naive_oversamplers[1].process(
                                 block.get_mut(channel_index).unwrap(),
-                                distortion_type,
+                                distortion,
                                 pre_gain,
                                 post_gain,
                             ),
                             _ => panic!("Dual channel only"),
                         };
                     }
+                    Oversampler::LanczosOversampler => {
+                        self.lanczos_oversamplers[channel_index].process(
+                            block.get_mut(channel_index).unwrap(),
+                            distortion,
+                            pre_gain,
+                            post_gain,
+                        );
+                    }
+                    Oversampler::PolyphaseOversampler => {
+                        self.polyphase_oversamplers[channel_index].process(
+                            block.get_mut(channel_index).unwrap(),
+                            distortion,
+                            pre_gain,
+                            post_gain,
+                        );
+                    }
                 }
+
+                self.emphases[channel_index].deemphasize(block.get_mut(channel_index).unwrap());
+
+                let dc_blocker = &mut self.dc_blockers[channel_index];
+                for sample in block.get_mut(channel_index).unwrap().iter_mut() {
+                    *sample = dc_blocker.filter(sample);
+                }
+
+                // The delayed dry signal is aligned with the wet one, its envelope drives the
+                // cutoff
+                let tone_filter = &mut self.tone_filters[channel_index];
+                tone_filter.set(tone_mode, tone_cutoff, tone_resonance, tone_envelope);
+                tone_filter.process(block.get_mut(channel_index).unwrap(), dry);
+
+                mix(
+                    dry,
+                    block.get_mut(channel_index).unwrap(),
+                    dry_gain,
+                    wet_gain,
+                );
             }
         }
 
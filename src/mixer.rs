@@ -0,0 +1,67 @@
+use nih_plug::prelude::Enum;
+use std::f32::consts::FRAC_PI_2;
+
+/// How the dry and wet signals are weighted by the `mix` parameter
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum MixLaw {
+    #[name = "Linear"]
+    Linear,
+    #[name = "Equal power"]
+    EqualPower,
+}
+
+impl MixLaw {
+    /// Returns the (dry, wet) gains for a mix amount between 0 (dry) and 1 (wet)
+    pub fn gains(self, mix: f32) -> (f32, f32) {
+        match self {
+            Self::Linear => (1.0 - mix, mix),
+            Self::EqualPower => ((mix * FRAC_PI_2).cos(), (mix * FRAC_PI_2).sin()),
+        }
+    }
+}
+
+/// Crossfade `wet` with `dry` in place
+pub fn mix(dry: &[f32], wet: &mut [f32], dry_gain: f32, wet_gain: f32) {
+    for (wet_sample, dry_sample) in wet.iter_mut().zip(dry) {
+        *wet_sample = *wet_sample * wet_gain + *dry_sample * dry_gain;
+    }
+}
+
+//
+// Fixed capacity delay line used to keep the dry signal aligned with the (oversampled) wet one.
+// The buffer is allocated once in `new`, changing the delay afterwards never allocates
+//
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    position: usize,
+    delay: usize,
+}
+
+impl DelayLine {
+    pub fn new(max_delay: usize) -> Self {
+        Self {
+            buffer: vec![0.0; max_delay + 1],
+            position: 0,
+            delay: 0,
+        }
+    }
+
+    /// Clamped to the capacity given to `new`
+    pub fn set_delay(&mut self, delay: usize) {
+        self.delay = delay.min(self.buffer.len() - 1);
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let length = self.buffer.len();
+        for sample in samples {
+            self.buffer[self.position] = *sample;
+            *sample = self.buffer[(self.position + length - self.delay) % length];
+            self.position = (self.position + 1) % length;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.buffer.fill(0.0);
+        self.position = 0;
+    }
+}
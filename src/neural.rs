@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Deserialize;
+
+/// Rate assumed for models which don't tell theirs, the usual capture rate
+const DEFAULT_MODEL_SAMPLE_RATE: f32 = 44100.0;
+
+//
+// Recurrent amp model: one LSTM or GRU layer followed by a dense layer, with an optional skip
+// connection adding the input to the output. The weights use PyTorch's layout, as exported by the
+// open amp modelling tools:
+//
+//   {
+//     "model_data": { "unit_type": "LSTM", "input_size": 1, "hidden_size": 20,
+//                     "num_layers": 1, "skip": 1, "sample_rate": 44100 },
+//     "state_dict": { "rec.weight_ih_l0": [...], "rec.weight_hh_l0": [...],
+//                     "rec.bias_ih_l0": [...], "rec.bias_hh_l0": [...],
+//                     "lin.weight": [...], "lin.bias": [...] }
+//   }
+//
+// Loading allocates, it happens on a background thread. Running the model and resetting its state
+// never allocate.
+//
+#[derive(Clone)]
+pub struct NeuralModel {
+    cell: Cell,
+    hidden_size: usize,
+    skip: bool,
+    sample_rate: f32,
+    /// Gates times input size 1
+    weight_ih: Vec<f32>,
+    /// Gates times hidden size, row major
+    weight_hh: Vec<f32>,
+    bias_ih: Vec<f32>,
+    bias_hh: Vec<f32>,
+    dense_weight: Vec<f32>,
+    dense_bias: f32,
+    hidden: Vec<f32>,
+    /// LSTM only
+    cell_state: Vec<f32>,
+    /// Input and hidden contributions to the gates, for the current sample
+    gates_input: Vec<f32>,
+    gates_hidden: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cell {
+    Lstm,
+    Gru,
+}
+
+impl Cell {
+    fn gates(self) -> usize {
+        match self {
+            Self::Lstm => 4,
+            Self::Gru => 3,
+        }
+    }
+}
+
+impl fmt::Display for NeuralModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cell = match self.cell {
+            Cell::Lstm => "LSTM",
+            Cell::Gru => "GRU",
+        };
+        write!(
+            f,
+            "{} with {} units, trained at {} Hz",
+            cell, self.hidden_size, self.sample_rate
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct ModelFile {
+    model_data: ModelData,
+    state_dict: HashMap<String, Tensor>,
+}
+
+#[derive(Deserialize)]
+struct ModelData {
+    unit_type: String,
+    #[serde(default = "one")]
+    input_size: usize,
+    hidden_size: usize,
+    #[serde(default = "one")]
+    num_layers: usize,
+    #[serde(default)]
+    skip: usize,
+    sample_rate: Option<f32>,
+}
+
+fn one() -> usize {
+    1
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Tensor {
+    Vector(Vec<f32>),
+    Matrix(Vec<Vec<f32>>),
+}
+
+impl Tensor {
+    fn flatten(self) -> Vec<f32> {
+        match self {
+            Self::Vector(vector) => vector,
+            Self::Matrix(matrix) => matrix.into_iter().flatten().collect(),
+        }
+    }
+}
+
+impl NeuralModel {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let mut file: ModelFile = serde_json::from_str(json).map_err(|error| error.to_string())?;
+        let data = file.model_data;
+        let cell = match data.unit_type.to_uppercase().as_str() {
+            "LSTM" => Cell::Lstm,
+            "GRU" => Cell::Gru,
+            other => return Err(format!("Unsupported unit type {}", other)),
+        };
+        if data.hidden_size == 0 {
+            return Err("The model has no hidden unit".to_string());
+        }
+        if data.input_size != 1 || data.num_layers != 1 {
+            return Err("Only single layer models with one input are supported".to_string());
+        }
+        let hidden_size = data.hidden_size;
+        let gates = cell.gates() * hidden_size;
+
+        let mut tensor = |name: &str, len: usize| match file.state_dict.remove(name) {
+            Some(tensor) => {
+                let values = tensor.flatten();
+                if values.len() == len {
+                    Ok(values)
+                } else {
+                    Err(format!(
+                        "{} has {} values, {} expected",
+                        name,
+                        values.len(),
+                        len
+                    ))
+                }
+            }
+            None => Err(format!("{} is missing", name)),
+        };
+        let weight_ih = tensor("rec.weight_ih_l0", gates)?;
+        let weight_hh = tensor("rec.weight_hh_l0", gates * hidden_size)?;
+        let bias_ih = tensor("rec.bias_ih_l0", gates)?;
+        let bias_hh = tensor("rec.bias_hh_l0", gates)?;
+        let dense_weight = tensor("lin.weight", hidden_size)?;
+        let dense_bias = tensor("lin.bias", 1)?[0];
+
+        Ok(Self {
+            cell,
+            hidden_size,
+            skip: data.skip != 0,
+            sample_rate: data.sample_rate.unwrap_or(DEFAULT_MODEL_SAMPLE_RATE),
+            weight_ih,
+            weight_hh,
+            bias_ih,
+            bias_hh,
+            dense_weight,
+            dense_bias,
+            hidden: vec![0.0; hidden_size],
+            cell_state: vec![0.0; hidden_size],
+            gates_input: vec![0.0; gates],
+            gates_hidden: vec![0.0; gates],
+        })
+    }
+
+    /// Rate the model was trained at
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn reset(&mut self) {
+        self.hidden.fill(0.0);
+        self.cell_state.fill(0.0);
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let size = self.hidden_size;
+        for (gate, ((input, hidden), row)) in self
+            .gates_input
+            .iter_mut()
+            .zip(self.gates_hidden.iter_mut())
+            .zip(self.weight_hh.chunks_exact(size))
+            .enumerate()
+        {
+            *input = self.weight_ih[gate] * x + self.bias_ih[gate];
+            *hidden = self.bias_hh[gate] + dot(row, &self.hidden);
+        }
+
+        match self.cell {
+            // Gates in order input, forget, cell, output
+            Cell::Lstm => {
+                for unit in 0..size {
+                    let gate = |k: usize| {
+                        self.gates_input[k * size + unit] + self.gates_hidden[k * size + unit]
+                    };
+                    let (i, f, g, o) = (
+                        sigmoid(gate(0)),
+                        sigmoid(gate(1)),
+                        gate(2).tanh(),
+                        sigmoid(gate(3)),
+                    );
+                    self.cell_state[unit] = f * self.cell_state[unit] + i * g;
+                    self.hidden[unit] = o * self.cell_state[unit].tanh();
+                }
+            }
+            // Gates in order reset, update, new
+            Cell::Gru => {
+                for unit in 0..size {
+                    let (input, hidden) = (&self.gates_input, &self.gates_hidden);
+                    let r = sigmoid(input[unit] + hidden[unit]);
+                    let z = sigmoid(input[size + unit] + hidden[size + unit]);
+                    let n = (input[2 * size + unit] + r * hidden[2 * size + unit]).tanh();
+                    self.hidden[unit] = (1.0 - z) * n + z * self.hidden[unit];
+                }
+            }
+        }
+
+        let y = dot(&self.dense_weight, &self.hidden) + self.dense_bias;
+        if self.skip {
+            y + x
+        } else {
+            y
+        }
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
@@ -1,24 +1,89 @@
 use nih_plug::prelude::Enum;
+use std::f32::consts::PI;
 
 use crate::{
-    distortions::Distortion,
+    distortions::DistortionProcessor,
     filters::{BiquadFilter, Filter, FilterType},
 };
 
-#[derive(Enum, Debug, PartialEq)]
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
 pub enum Oversampler {
     NaiveOversampler,
     None,
+    LanczosOversampler,
+    PolyphaseOversampler,
+}
+
+/// Oversampling ratio of the `PolyphaseOversampler`, each x2 step is a half-band stage
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum OversamplingFactor {
+    #[name = "x2"]
+    X2,
+    #[name = "x4"]
+    X4,
+    #[name = "x8"]
+    X8,
+    #[name = "x16"]
+    X16,
+}
+
+impl OversamplingFactor {
+    pub fn stages(self) -> usize {
+        match self {
+            Self::X2 => 1,
+            Self::X4 => 2,
+            Self::X8 => 3,
+            Self::X16 => 4,
+        }
+    }
+
+    pub fn ratio(self) -> usize {
+        1 << self.stages()
+    }
 }
 
 pub const BLOCK_SIZE: usize = 64;
 
+pub const DEFAULT_LANCZOS_RADIUS: usize = 3;
+pub const MAX_LANCZOS_RADIUS: usize = 8;
+
+const MAX_OVERSAMPLING_STAGES: usize = 4;
+const MAX_OVERSAMPLING_RATIO: usize = 1 << MAX_OVERSAMPLING_STAGES;
+// Number of distinct non zero side coefficients of the first half-band stage (63 taps) and of the
+// following ones (31 taps), which see a much wider transition band
+const FIRST_HALF_BAND_TAPS: usize = 16;
+const HALF_BAND_TAPS: usize = 8;
+const HALF_BAND_KAISER_BETA: f32 = 8.0;
+
+/// The `Resampler` runs the distortion between 1 / MAX_RESAMPLING_RATIO and MAX_RESAMPLING_RATIO
+/// times the host's sample rate
+const MAX_RESAMPLING_RATIO: f32 = 8.0;
+// Lobes of the resampling kernels on each side, at the lower of the two rates, and the number of
+// points per lobe in their table
+const RESAMPLER_RADIUS: usize = 8;
+const RESAMPLER_TABLE_RESOLUTION: usize = 256;
+/// Cutoff of the resampling kernels, relative to the lower of the two Nyquist frequencies
+const RESAMPLER_CUTOFF: f32 = 0.9;
+const RESAMPLER_KAISER_BETA: f32 = 8.0;
+/// Long enough for the resampled samples of a block plus the kernels' span at the extreme ratios
+const RESAMPLER_HISTORY: usize = 1024;
+
 pub trait Oversampling {
     fn new(sample_rate: f32) -> Self;
     fn upsample(&mut self, block: &mut [f32]);
     fn downsample(&mut self, block: &mut [f32]);
-    fn process(&mut self, block: &mut [f32], f: Distortion, pre_gain: f32, post_gain: f32);
+    fn process(
+        &mut self,
+        block: &mut [f32],
+        distortion: &mut dyn DistortionProcessor,
+        pre_gain: f32,
+        post_gain: f32,
+    );
     fn reset(&mut self);
+    /// Group delay added to the signal, in samples at the host's sample rate
+    fn latency(&self) -> u32;
+    /// The distortion runs at `ratio` times the host's sample rate
+    fn ratio(&self) -> usize;
 }
 
 //
@@ -45,10 +110,16 @@ impl Oversampling for NaiveOversampler {
         }
     }
 
-    fn process(&mut self, block: &mut [f32], f: Distortion, pre_gain: f32, post_gain: f32) {
+    fn process(
+        &mut self,
+        block: &mut [f32],
+        distortion: &mut dyn DistortionProcessor,
+        pre_gain: f32,
+        post_gain: f32,
+    ) {
         self.upsample(block);
 
-        f(pre_gain, post_gain, &mut self.oversampled_block);
+        distortion.process(pre_gain, post_gain, &mut self.oversampled_block);
 
         self.downsample(block);
     }
@@ -79,4 +150,777 @@ impl Oversampling for NaiveOversampler {
         self.filter_upsample.reset();
         self.filter_downsample.reset();
     }
+
+    fn latency(&self) -> u32 {
+        // The biquads are minimum phase: their group delay in the audible band is a fraction of a
+        // sample
+        0
+    }
+
+    fn ratio(&self) -> usize {
+        2
+    }
+}
+
+//
+// x2 oversampling using a Lanczos (windowed sinc) kernel of radius `a`:
+// Lanczos interpolation of the half samples -> non linear function ->
+// Lanczos half-band FIR -> downsample
+//
+// Both kernels are linear phase: the whole chain delays the signal by 2a - 1 samples
+//
+pub struct LanczosOversampler {
+    oversampled_block: [f32; BLOCK_SIZE * 2],
+    radius: usize,
+    upsample_kernel: [f32; 2 * MAX_LANCZOS_RADIUS],
+    downsample_kernel: [f32; 4 * MAX_LANCZOS_RADIUS - 1],
+    // Most recent sample first
+    upsample_history: [f32; 2 * MAX_LANCZOS_RADIUS],
+    downsample_history: [f32; 4 * MAX_LANCZOS_RADIUS - 1],
+}
+
+impl LanczosOversampler {
+    /// Change the kernel radius (clamped to `1..=MAX_LANCZOS_RADIUS`). Only recomputes the kernels
+    /// when the radius actually changed, so this can be called for every block.
+    pub fn set_radius(&mut self, radius: usize) {
+        let radius = radius.clamp(1, MAX_LANCZOS_RADIUS);
+        if radius == self.radius {
+            return;
+        }
+        self.radius = radius;
+        let a = radius as f32;
+
+        // Interpolation of the point located half way between x[n - a] and x[n - a + 1]
+        self.upsample_kernel = [0.0; 2 * MAX_LANCZOS_RADIUS];
+        for k in 0..2 * radius {
+            self.upsample_kernel[k] = lanczos(k as f32 - a + 0.5, a);
+        }
+        normalize(&mut self.upsample_kernel[..2 * radius]);
+
+        // Low pass at a quarter of the oversampled rate, centered on tap 2a - 1
+        self.downsample_kernel = [0.0; 4 * MAX_LANCZOS_RADIUS - 1];
+        for j in 0..4 * radius - 1 {
+            self.downsample_kernel[j] = lanczos((j as f32 - (2.0 * a - 1.0)) / 2.0, a);
+        }
+        normalize(&mut self.downsample_kernel[..4 * radius - 1]);
+
+        self.reset();
+    }
+}
+
+impl Oversampling for LanczosOversampler {
+    fn new(_sample_rate: f32) -> Self {
+        let mut oversampler = Self {
+            oversampled_block: [0.0; BLOCK_SIZE * 2],
+            radius: 0,
+            upsample_kernel: [0.0; 2 * MAX_LANCZOS_RADIUS],
+            downsample_kernel: [0.0; 4 * MAX_LANCZOS_RADIUS - 1],
+            upsample_history: [0.0; 2 * MAX_LANCZOS_RADIUS],
+            downsample_history: [0.0; 4 * MAX_LANCZOS_RADIUS - 1],
+        };
+        oversampler.set_radius(DEFAULT_LANCZOS_RADIUS);
+        oversampler
+    }
+
+    fn process(
+        &mut self,
+        block: &mut [f32],
+        distortion: &mut dyn DistortionProcessor,
+        pre_gain: f32,
+        post_gain: f32,
+    ) {
+        self.upsample(block);
+
+        distortion.process(
+            pre_gain,
+            post_gain,
+            &mut self.oversampled_block[..block.len() * 2],
+        );
+
+        self.downsample(block);
+    }
+
+    fn upsample(&mut self, block: &mut [f32]) {
+        let taps = 2 * self.radius;
+        for (n, sample) in block.iter().enumerate() {
+            self.upsample_history.copy_within(0..taps - 1, 1);
+            self.upsample_history[0] = *sample;
+
+            let interpolated: f32 = self.upsample_kernel[..taps]
+                .iter()
+                .zip(&self.upsample_history[..taps])
+                .map(|(k, x)| k * x)
+                .sum();
+            self.oversampled_block[2 * n] = self.upsample_history[self.radius];
+            self.oversampled_block[2 * n + 1] = interpolated;
+        }
+    }
+
+    fn downsample(&mut self, block: &mut [f32]) {
+        let taps = 4 * self.radius - 1;
+        for (n, sample) in block.iter_mut().enumerate() {
+            self.downsample_history.copy_within(0..taps - 2, 2);
+            self.downsample_history[1] = self.oversampled_block[2 * n];
+            self.downsample_history[0] = self.oversampled_block[2 * n + 1];
+
+            *sample = self.downsample_kernel[..taps]
+                .iter()
+                .zip(&self.downsample_history[..taps])
+                .map(|(k, x)| k * x)
+                .sum();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.upsample_history = [0.0; 2 * MAX_LANCZOS_RADIUS];
+        self.downsample_history = [0.0; 4 * MAX_LANCZOS_RADIUS - 1];
+    }
+
+    fn latency(&self) -> u32 {
+        (2 * self.radius - 1) as u32
+    }
+
+    fn ratio(&self) -> usize {
+        2
+    }
+}
+
+//
+// Up to x16 oversampling made of a cascade of x2 polyphase half-band FIR stages:
+// (zero stuffing -> half-band FIR) * stages -> non linear function ->
+// (half-band FIR -> decimation) * stages
+//
+// Every other coefficient of a half-band filter is null, so each stage only computes the non null
+// ones, on the branch (polyphase component) where they apply
+//
+pub struct PolyphaseOversampler {
+    oversampled_block: [f32; BLOCK_SIZE * MAX_OVERSAMPLING_RATIO],
+    scratch_block: [f32; BLOCK_SIZE * MAX_OVERSAMPLING_RATIO],
+    factor: OversamplingFactor,
+    stages: [HalfBandStage; MAX_OVERSAMPLING_STAGES],
+    /// Last oversampled samples, delaying the oversampled signal by `padding()` samples
+    padding_history: [f32; MAX_OVERSAMPLING_RATIO],
+}
+
+impl PolyphaseOversampler {
+    /// Change the oversampling ratio. The filters states are cleared when the ratio changes, so
+    /// this can be called for every block.
+    pub fn set_factor(&mut self, factor: OversamplingFactor) {
+        if factor != self.factor {
+            self.factor = factor;
+            self.reset();
+        }
+    }
+
+    /// Delay of the stages, in samples at the oversampled rate: each one delays its input by
+    /// 2 * taps - 1 samples, the k-th one running at 2^k times the host's sample rate
+    fn stages_delay(&self) -> usize {
+        let ratio = self.factor.ratio();
+        self.stages[..self.factor.stages()]
+            .iter()
+            .enumerate()
+            .map(|(k, stage)| ((2 * stage.taps - 1) * ratio) >> k)
+            .sum()
+    }
+
+    /// The stages' delay is a fraction of a sample at the host's rate. Delaying the oversampled
+    /// signal by this many more samples rounds it up to a whole number of samples, so that the
+    /// latency compensation is exact
+    fn padding(&self) -> usize {
+        let ratio = self.factor.ratio();
+        (ratio - self.stages_delay() % ratio) % ratio
+    }
+
+    fn pad(&mut self, len: usize) {
+        let padding = self.padding();
+        if padding == 0 || len == 0 {
+            return;
+        }
+        // The block is at least `ratio` samples long, longer than the padding
+        self.scratch_block[..padding].copy_from_slice(&self.padding_history[..padding]);
+        self.scratch_block[padding..len].copy_from_slice(&self.oversampled_block[..len - padding]);
+        self.padding_history[..padding]
+            .copy_from_slice(&self.oversampled_block[len - padding..len]);
+        self.oversampled_block[..len].copy_from_slice(&self.scratch_block[..len]);
+    }
+}
+
+impl Oversampling for PolyphaseOversampler {
+    fn new(_sample_rate: f32) -> Self {
+        Self {
+            oversampled_block: [0.0; BLOCK_SIZE * MAX_OVERSAMPLING_RATIO],
+            scratch_block: [0.0; BLOCK_SIZE * MAX_OVERSAMPLING_RATIO],
+            factor: OversamplingFactor::X2,
+            stages: [
+                HalfBandStage::new(FIRST_HALF_BAND_TAPS),
+                HalfBandStage::new(HALF_BAND_TAPS),
+                HalfBandStage::new(HALF_BAND_TAPS),
+                HalfBandStage::new(HALF_BAND_TAPS),
+            ],
+            padding_history: [0.0; MAX_OVERSAMPLING_RATIO],
+        }
+    }
+
+    fn process(
+        &mut self,
+        block: &mut [f32],
+        distortion: &mut dyn DistortionProcessor,
+        pre_gain: f32,
+        post_gain: f32,
+    ) {
+        self.upsample(block);
+        self.pad(block.len() * self.factor.ratio());
+
+        distortion.process(
+            pre_gain,
+            post_gain,
+            &mut self.oversampled_block[..block.len() * self.factor.ratio()],
+        );
+
+        self.downsample(block);
+    }
+
+    fn upsample(&mut self, block: &mut [f32]) {
+        let mut len = block.len();
+        self.oversampled_block[..len].copy_from_slice(block);
+        for stage in &mut self.stages[..self.factor.stages()] {
+            for n in 0..len {
+                let (even, odd) = stage.upsample(self.oversampled_block[n]);
+                self.scratch_block[2 * n] = even;
+                self.scratch_block[2 * n + 1] = odd;
+            }
+            len *= 2;
+            self.oversampled_block[..len].copy_from_slice(&self.scratch_block[..len]);
+        }
+    }
+
+    fn downsample(&mut self, block: &mut [f32]) {
+        let mut len = block.len() * self.factor.ratio();
+        for stage in self.stages[..self.factor.stages()].iter_mut().rev() {
+            len /= 2;
+            for n in 0..len {
+                self.scratch_block[n] = stage.downsample(
+                    self.oversampled_block[2 * n],
+                    self.oversampled_block[2 * n + 1],
+                );
+            }
+            self.oversampled_block[..len].copy_from_slice(&self.scratch_block[..len]);
+        }
+        block.copy_from_slice(&self.oversampled_block[..len]);
+    }
+
+    fn reset(&mut self) {
+        for stage in &mut self.stages {
+            stage.reset();
+        }
+        self.padding_history = [0.0; MAX_OVERSAMPLING_RATIO];
+    }
+
+    fn latency(&self) -> u32 {
+        ((self.stages_delay() + self.padding()) / self.factor.ratio()) as u32
+    }
+
+    fn ratio(&self) -> usize {
+        self.factor.ratio()
+    }
+}
+
+//
+// Kaiser windowed half-band FIR of 4 * taps - 1 coefficients h, centered on c = 2 * taps - 1:
+// h[c] = 1/2, h[c + d] = 0 for every even d, and the `taps` distinct non null coefficients
+// h[c +/- (2i + 1)] are stored in `coefficients`.
+// Delays the signal by 2 * taps - 1 samples at the stage's input rate
+//
+struct HalfBandStage {
+    taps: usize,
+    coefficients: [f32; FIRST_HALF_BAND_TAPS],
+    // Most recent sample first
+    upsample_history: [f32; 2 * FIRST_HALF_BAND_TAPS],
+    downsample_even_history: [f32; 2 * FIRST_HALF_BAND_TAPS],
+    downsample_odd_history: [f32; FIRST_HALF_BAND_TAPS],
+}
+
+impl HalfBandStage {
+    fn new(taps: usize) -> Self {
+        let mut coefficients = [0.0; FIRST_HALF_BAND_TAPS];
+        let half_length = 2.0 * taps as f32;
+        for (i, coefficient) in coefficients[..taps].iter_mut().enumerate() {
+            let d = (2 * i + 1) as f32;
+            let window =
+                bessel_i0(HALF_BAND_KAISER_BETA * (1.0 - (d / half_length).powi(2)).sqrt())
+                    / bessel_i0(HALF_BAND_KAISER_BETA);
+            *coefficient = 0.5 * sinc(d / 2.0) * window;
+        }
+        // The side coefficients must add up to 1/2 (1/4 for each side) for unity DC gain
+        let sum: f32 = coefficients[..taps].iter().sum();
+        for coefficient in &mut coefficients[..taps] {
+            *coefficient *= 0.25 / sum;
+        }
+
+        Self {
+            taps,
+            coefficients,
+            upsample_history: [0.0; 2 * FIRST_HALF_BAND_TAPS],
+            downsample_even_history: [0.0; 2 * FIRST_HALF_BAND_TAPS],
+            downsample_odd_history: [0.0; FIRST_HALF_BAND_TAPS],
+        }
+    }
+
+    /// Symmetric FIR over the non null coefficients of a 2 * taps samples history
+    fn side_branch(&self, history: &[f32]) -> f32 {
+        let taps = self.taps;
+        (0..taps)
+            .map(|i| self.coefficients[i] * (history[taps - 1 - i] + history[taps + i]))
+            .sum()
+    }
+
+    /// Returns the two oversampled samples produced by one input sample
+    fn upsample(&mut self, sample: f32) -> (f32, f32) {
+        let taps = self.taps;
+        self.upsample_history.copy_within(0..2 * taps - 1, 1);
+        self.upsample_history[0] = sample;
+
+        // Zero stuffing halves the energy, hence the gain of 2 on both branches
+        let even = 2.0 * self.side_branch(&self.upsample_history);
+        let odd = self.upsample_history[taps - 1];
+        (even, odd)
+    }
+
+    /// Returns the decimated sample produced by two oversampled samples
+    fn downsample(&mut self, even: f32, odd: f32) -> f32 {
+        let taps = self.taps;
+        self.downsample_even_history.copy_within(0..2 * taps - 1, 1);
+        self.downsample_even_history[0] = even;
+
+        let sample = 0.5 * self.downsample_odd_history[taps - 1]
+            + self.side_branch(&self.downsample_even_history);
+
+        self.downsample_odd_history.copy_within(0..taps - 1, 1);
+        self.downsample_odd_history[0] = odd;
+        sample
+    }
+
+    fn reset(&mut self) {
+        self.upsample_history = [0.0; 2 * FIRST_HALF_BAND_TAPS];
+        self.downsample_even_history = [0.0; 2 * FIRST_HALF_BAND_TAPS];
+        self.downsample_odd_history = [0.0; FIRST_HALF_BAND_TAPS];
+    }
+}
+
+//
+// Fractional resampling to any rate within MAX_RESAMPLING_RATIO of the host's, up or down, for
+// the NEURAL models which must run at the rate they were trained at:
+// windowed sinc interpolation at the target rate -> non linear function ->
+// windowed sinc interpolation back at the host's rate
+//
+// Both kernels are Kaiser windowed sincs, cut off below the Nyquist frequency of the lower of the
+// two rates and RESAMPLER_RADIUS of its samples wide. They are read from a table at any fractional
+// position. The second interpolation is delayed so that the whole chain delays the signal by a
+// whole number of samples
+//
+pub struct Resampler {
+    resampled_block: [f32; BLOCK_SIZE * MAX_OVERSAMPLING_RATIO],
+    sample_rate: f32,
+    rate: f32,
+    /// Kernel as a function of the distance in samples at the lower rate, from 0 to the radius
+    kernel: [f32; RESAMPLER_RADIUS * RESAMPLER_TABLE_RESOLUTION + 2],
+    upsample_history: [f32; RESAMPLER_HISTORY],
+    upsample_newest: usize,
+    downsample_history: [f32; RESAMPLER_HISTORY],
+    downsample_newest: usize,
+    /// Position of the next resampled sample, in host samples after the newest input sample
+    upsample_phase: f64,
+    /// Position of the next output sample, in resampled samples after the newest resampled one
+    downsample_phase: f64,
+    latency: u32,
+}
+
+impl Resampler {
+    pub fn new(sample_rate: f32) -> Self {
+        let mut kernel = [0.0; RESAMPLER_RADIUS * RESAMPLER_TABLE_RESOLUTION + 2];
+        let radius = RESAMPLER_RADIUS as f32;
+        for (i, tap) in kernel.iter_mut().enumerate() {
+            let x = (i as f32 / RESAMPLER_TABLE_RESOLUTION as f32).min(radius);
+            let window = bessel_i0(RESAMPLER_KAISER_BETA * (1.0 - (x / radius).powi(2)).sqrt())
+                / bessel_i0(RESAMPLER_KAISER_BETA);
+            *tap = sinc(RESAMPLER_CUTOFF * x) * window;
+        }
+
+        let mut resampler = Self {
+            resampled_block: [0.0; BLOCK_SIZE * MAX_OVERSAMPLING_RATIO],
+            sample_rate,
+            rate: sample_rate,
+            kernel,
+            upsample_history: [0.0; RESAMPLER_HISTORY],
+            upsample_newest: 0,
+            downsample_history: [0.0; RESAMPLER_HISTORY],
+            downsample_newest: 0,
+            upsample_phase: 0.0,
+            downsample_phase: 0.0,
+            latency: 0,
+        };
+        resampler.update_latency();
+        resampler.reset();
+        resampler
+    }
+
+    /// Change the rate the distortion runs at, clamped to MAX_RESAMPLING_RATIO times the host's
+    /// one at most. The states are cleared when the rate changes, so this can be called for every
+    /// block.
+    pub fn set_rate(&mut self, rate: f32) {
+        let rate = rate.clamp(
+            self.sample_rate / MAX_RESAMPLING_RATIO,
+            self.sample_rate * MAX_RESAMPLING_RATIO,
+        );
+        if rate != self.rate {
+            self.rate = rate;
+            self.update_latency();
+            self.reset();
+        }
+    }
+
+    /// The rate the distortion actually runs at
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    /// Scales from host and resampled samples to samples at the lower of the two rates
+    fn scales(&self) -> (f64, f64) {
+        let ratio = self.rate as f64 / self.sample_rate as f64;
+        (ratio.min(1.0), ratio.recip().min(1.0))
+    }
+
+    /// Half widths of the kernels, in samples at their input's rate
+    fn half_widths(&self) -> (f64, f64) {
+        let (upsample_scale, downsample_scale) = self.scales();
+        let radius = RESAMPLER_RADIUS as f64;
+        (radius / upsample_scale, radius / downsample_scale)
+    }
+
+    /// The second interpolation needs every resampled sample within its half width of the point
+    /// it interpolates, the last one being computed up to one resampled sample late
+    fn update_latency(&mut self) {
+        let (upsample_half_width, downsample_half_width) = self.half_widths();
+        let step = self.sample_rate as f64 / self.rate as f64;
+        self.latency = (upsample_half_width + (downsample_half_width + 1.0) * step).ceil() as u32;
+    }
+
+    pub fn process(
+        &mut self,
+        block: &mut [f32],
+        distortion: &mut dyn DistortionProcessor,
+        pre_gain: f32,
+        post_gain: f32,
+    ) {
+        let (upsample_scale, downsample_scale) = self.scales();
+        let (upsample_half_width, downsample_half_width) = self.half_widths();
+        let ratio = self.rate as f64 / self.sample_rate as f64;
+
+        // The resampled sample at the phase is interpolated a half width earlier, once all the
+        // input samples it needs are known
+        let mut len = 0;
+        for &sample in block.iter() {
+            self.upsample_newest = (self.upsample_newest + 1) % RESAMPLER_HISTORY;
+            self.upsample_history[self.upsample_newest] = sample;
+            self.upsample_phase -= 1.0;
+            while self.upsample_phase <= 0.0 {
+                self.resampled_block[len] = interpolate(
+                    &self.kernel,
+                    &self.upsample_history,
+                    self.upsample_newest,
+                    self.upsample_phase - upsample_half_width,
+                    upsample_half_width,
+                    upsample_scale,
+                );
+                len += 1;
+                self.upsample_phase += ratio.recip();
+            }
+        }
+
+        distortion.process(pre_gain, post_gain, &mut self.resampled_block[..len]);
+
+        for &sample in &self.resampled_block[..len] {
+            self.downsample_newest = (self.downsample_newest + 1) % RESAMPLER_HISTORY;
+            self.downsample_history[self.downsample_newest] = sample;
+        }
+        self.downsample_phase -= len as f64;
+        for sample in block.iter_mut() {
+            *sample = interpolate(
+                &self.kernel,
+                &self.downsample_history,
+                self.downsample_newest,
+                self.downsample_phase,
+                downsample_half_width,
+                downsample_scale,
+            );
+            self.downsample_phase += ratio;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.upsample_history = [0.0; RESAMPLER_HISTORY];
+        self.downsample_history = [0.0; RESAMPLER_HISTORY];
+        // The first resampled sample is at the first input sample, and the first output sample
+        // `latency` samples before it
+        let (upsample_half_width, _) = self.half_widths();
+        let ratio = self.rate as f64 / self.sample_rate as f64;
+        self.upsample_phase = 1.0;
+        self.downsample_phase = (upsample_half_width - self.latency as f64) * ratio + 1.0;
+    }
+
+    /// Group delay added to the signal, in samples at the host's sample rate
+    pub fn latency(&self) -> u32 {
+        self.latency
+    }
+}
+
+/// Value at `position` samples after the newest one of a history, `half_width` being the
+/// kernel's in samples of the history and `scale` the ratio to samples at the lower rate
+fn interpolate(
+    kernel: &[f32],
+    history: &[f32; RESAMPLER_HISTORY],
+    newest: usize,
+    position: f64,
+    half_width: f64,
+    scale: f64,
+) -> f32 {
+    let first = (position - half_width).ceil() as isize;
+    let last = (position + half_width).floor() as isize;
+    let (mut sum, mut weights) = (0.0, 0.0);
+    for offset in first..=last {
+        let distance = ((position - offset as f64).abs() * scale) as f32;
+        let index = distance * RESAMPLER_TABLE_RESOLUTION as f32;
+        let i = (index as usize).min(kernel.len() - 2);
+        let weight = kernel[i] + (kernel[i + 1] - kernel[i]) * (index - i as f32);
+        let sample = (newest as isize + offset).rem_euclid(RESAMPLER_HISTORY as isize);
+        sum += weight * history[sample as usize];
+        weights += weight;
+    }
+    // Scaled to unity DC gain whatever the fractional position
+    sum / weights
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Zeroth order modified Bessel function of the first kind, used by the Kaiser window
+fn bessel_i0(x: f32) -> f32 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half_x = x / 2.0;
+    for k in 1..32 {
+        term *= (half_x / k as f32).powi(2);
+        sum += term;
+        if term < sum * 1e-9 {
+            break;
+        }
+    }
+    sum
+}
+
+/// Lanczos kernel of radius `a`: sinc(x) * sinc(x / a) for |x| < a, 0 elsewhere
+fn lanczos(x: f32, a: f32) -> f32 {
+    if x.abs() >= a {
+        0.0
+    } else {
+        sinc(x) * sinc(x / a)
+    }
+}
+
+/// Scale a FIR kernel to unity DC gain
+fn normalize(kernel: &mut [f32]) {
+    let sum: f32 = kernel.iter().sum();
+    for tap in kernel {
+        *tap /= sum;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+    const FACTORS: [OversamplingFactor; 4] = [
+        OversamplingFactor::X2,
+        OversamplingFactor::X4,
+        OversamplingFactor::X8,
+        OversamplingFactor::X16,
+    ];
+
+    /// Leaves the oversampled signal untouched
+    struct Identity;
+
+    impl DistortionProcessor for Identity {
+        fn process(&mut self, _pre_gain: f32, _post_gain: f32, _samples: &mut [f32]) {}
+    }
+
+    /// Replaces the oversampled signal by a sine, at a frequency the host's rate can't represent
+    struct Tone {
+        step: f32,
+        phase: f32,
+    }
+
+    impl DistortionProcessor for Tone {
+        fn process(&mut self, _pre_gain: f32, _post_gain: f32, samples: &mut [f32]) {
+            for sample in samples {
+                *sample = (2.0 * PI * self.phase).sin();
+                self.phase = (self.phase + self.step).fract();
+            }
+        }
+    }
+
+    fn sine(frequency: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| (2.0 * PI * frequency * n as f32 / SAMPLE_RATE).sin())
+            .collect()
+    }
+
+    fn run(
+        oversampler: &mut impl Oversampling,
+        distortion: &mut dyn DistortionProcessor,
+        input: &[f32],
+    ) -> Vec<f32> {
+        let mut output = input.to_vec();
+        for block in output.chunks_mut(BLOCK_SIZE) {
+            oversampler.process(block, distortion, 1.0, 1.0);
+        }
+        output
+    }
+
+    /// Amplitude of the second half of a steady sine, in dB
+    fn level_db(samples: &[f32]) -> f32 {
+        let tail = &samples[samples.len() / 2..];
+        let rms = (tail.iter().map(|x| x * x).sum::<f32>() / tail.len() as f32).sqrt();
+        20.0 * (rms * 2.0f32.sqrt()).log10()
+    }
+
+    #[test]
+    fn half_band_passband_is_flat() {
+        for factor in FACTORS {
+            for frequency in [100.0, 1000.0, 10000.0, 18000.0] {
+                let mut oversampler = PolyphaseOversampler::new(SAMPLE_RATE);
+                oversampler.set_factor(factor);
+                let output = run(&mut oversampler, &mut Identity, &sine(frequency, 8192));
+                let level = level_db(&output);
+                assert!(
+                    level.abs() < 0.1,
+                    "{:?} at {} Hz: {} dB",
+                    factor,
+                    frequency,
+                    level
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn half_band_stopband_rejects_aliases() {
+        for factor in FACTORS {
+            let rate = SAMPLE_RATE * factor.ratio() as f32;
+            for frequency in [30000.0, 40000.0] {
+                let mut oversampler = PolyphaseOversampler::new(SAMPLE_RATE);
+                oversampler.set_factor(factor);
+                let mut tone = Tone {
+                    step: frequency / rate,
+                    phase: 0.0,
+                };
+                let output = run(&mut oversampler, &mut tone, &[0.0; 8192]);
+                let level = level_db(&output);
+                assert!(
+                    level < -70.0,
+                    "{:?} at {} Hz: {} dB",
+                    factor,
+                    frequency,
+                    level
+                );
+            }
+        }
+    }
+
+    /// Largest difference between the output and the input delayed by the reported latency,
+    /// relative to the input's amplitude
+    fn latency_error(oversampler: &mut impl Oversampling) -> f32 {
+        let input = sine(1000.0, 8192);
+        let output = run(oversampler, &mut Identity, &input);
+        let latency = oversampler.latency() as usize;
+        (4096..8192)
+            .map(|n| (output[n] - input[n - latency]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn polyphase_latency_is_exact() {
+        for factor in FACTORS {
+            let mut oversampler = PolyphaseOversampler::new(SAMPLE_RATE);
+            oversampler.set_factor(factor);
+            let error = latency_error(&mut oversampler);
+            assert!(error < 1e-3, "{:?}: {}", factor, error);
+        }
+    }
+
+    #[test]
+    fn lanczos_latency_is_exact() {
+        for radius in 1..=MAX_LANCZOS_RADIUS {
+            let mut oversampler = LanczosOversampler::new(SAMPLE_RATE);
+            oversampler.set_radius(radius);
+            // The shortest kernels already attenuate 1 kHz by a fraction of a dB
+            let error = latency_error(&mut oversampler);
+            assert!(error < 1e-2, "radius {}: {}", radius, error);
+        }
+    }
+
+    /// Counts the samples it is given
+    struct Counter(usize);
+
+    impl DistortionProcessor for Counter {
+        fn process(&mut self, _pre_gain: f32, _post_gain: f32, samples: &mut [f32]) {
+            self.0 += samples.len();
+        }
+    }
+
+    #[test]
+    fn resampler_runs_at_the_exact_rate() {
+        for rate in [22050.0, 44100.0, 96000.0, 144000.0] {
+            let mut resampler = Resampler::new(SAMPLE_RATE);
+            resampler.set_rate(rate);
+            let input = sine(1000.0, 48000);
+            let mut output = input.clone();
+            let mut counter = Counter(0);
+            // Uneven blocks, as hosts may send
+            for (index, block) in output.chunks_mut(37).enumerate() {
+                let len = block.len().min(1 + index % BLOCK_SIZE);
+                for block in block.chunks_mut(len) {
+                    resampler.process(block, &mut counter, 1.0, 1.0);
+                }
+            }
+            // One second, but for the resampled samples after the last input one
+            let missing = rate - counter.0 as f32;
+            assert!(
+                (0.0..=rate / SAMPLE_RATE).contains(&missing),
+                "{}: {}",
+                rate,
+                counter.0
+            );
+
+            let latency = resampler.latency() as usize;
+            let error = (24000..48000)
+                .map(|n| (output[n] - input[n - latency]).abs())
+                .fold(0.0, f32::max);
+            assert!(error < 1e-3, "{}: {}", rate, error);
+        }
+    }
+
+    #[test]
+    fn resampler_rate_is_clamped() {
+        let mut resampler = Resampler::new(SAMPLE_RATE);
+        resampler.set_rate(SAMPLE_RATE * 100.0);
+        assert_eq!(resampler.rate(), SAMPLE_RATE * MAX_RESAMPLING_RATIO);
+        resampler.set_rate(SAMPLE_RATE / 100.0);
+        assert_eq!(resampler.rate(), SAMPLE_RATE / MAX_RESAMPLING_RATIO);
+    }
 }
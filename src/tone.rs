@@ -0,0 +1,106 @@
+use nih_plug::prelude::Enum;
+
+use crate::filters::{Filter, FilterType, StateVariableFilter, SvfOutputs};
+
+/// Attack and release of the envelope driving the tone filter's cutoff
+const ENVELOPE_ATTACK_MS: f32 = 5.0;
+const ENVELOPE_RELEASE_MS: f32 = 150.0;
+
+/// Output of the post distortion tone filter
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum ToneMode {
+    #[name = "Off"]
+    Off,
+    #[name = "Lowpass"]
+    Lowpass,
+    #[name = "Bandpass"]
+    Bandpass,
+    #[name = "Highpass"]
+    Highpass,
+}
+
+impl ToneMode {
+    /// The filter is skipped when off, the input is passed through if it is not
+    fn select(self, input: f32, outputs: SvfOutputs) -> f32 {
+        match self {
+            Self::Off => {
+                debug_assert!(false, "The tone filter is skipped when off");
+                input
+            }
+            Self::Lowpass => outputs.lowpass,
+            Self::Bandpass => outputs.bandpass,
+            Self::Highpass => outputs.highpass,
+        }
+    }
+}
+
+//
+// Tone and wah section after the distortion: a state variable filter whose cutoff is moved by the
+// envelope of another signal, `envelope` octaves up (or down) at full scale. The cutoff is updated
+// every sample, which the ZDF filter handles without zipper noise nor blowing up
+//
+pub struct ToneFilter {
+    filter: StateVariableFilter,
+    mode: ToneMode,
+    cutoff: f32,
+    q: f32,
+    envelope: f32,
+    /// Follower state and its smoothing coefficients
+    level: f32,
+    attack: f32,
+    release: f32,
+}
+
+impl ToneFilter {
+    pub fn new(sample_rate: f32) -> Self {
+        let coefficient = |ms: f32| (-1000.0 / (ms * sample_rate)).exp();
+        Self {
+            filter: StateVariableFilter::with_q(1000.0, sample_rate, 0.7, FilterType::LOWPASS),
+            mode: ToneMode::Off,
+            cutoff: 1000.0,
+            q: 0.7,
+            envelope: 0.0,
+            level: 0.0,
+            attack: coefficient(ENVELOPE_ATTACK_MS),
+            release: coefficient(ENVELOPE_RELEASE_MS),
+        }
+    }
+
+    /// Switching the mode on starts from a cleared state
+    pub fn set(&mut self, mode: ToneMode, cutoff: f32, q: f32, envelope: f32) {
+        if mode != self.mode {
+            if self.mode == ToneMode::Off {
+                self.reset();
+            }
+            self.mode = mode;
+        }
+        self.cutoff = cutoff;
+        self.q = q;
+        self.envelope = envelope;
+    }
+
+    /// Filter `samples`, following the envelope of `sidechain`
+    pub fn process(&mut self, samples: &mut [f32], sidechain: &[f32]) {
+        if self.mode == ToneMode::Off {
+            return;
+        }
+        for (sample, side) in samples.iter_mut().zip(sidechain) {
+            let input = side.abs().min(1.0);
+            let coefficient = if input > self.level {
+                self.attack
+            } else {
+                self.release
+            };
+            self.level = input + (self.level - input) * coefficient;
+
+            self.filter
+                .set(self.cutoff * (self.envelope * self.level).exp2(), self.q);
+            *sample = self.mode.select(*sample, self.filter.process(*sample));
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.filter.reset();
+        self.level = 0.0;
+    }
+}